@@ -15,6 +15,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub struct Error {
     status_code: StatusCode,
     message: String,
+    /// seconds after which a retry is expected to succeed, sent as `Retry-After`
+    retry_after: Option<u64>,
 }
 
 impl Error {
@@ -22,6 +24,7 @@ impl Error {
         Error {
             status_code: StatusCode::BAD_REQUEST,
             message: format!("Could not extract attachment from request body"),
+            retry_after: None,
         }
     }
 
@@ -36,6 +39,7 @@ impl Error {
         Error {
             status_code: StatusCode::BAD_REQUEST,
             message: format!("Invalid value-type detected in request: {:?}", e),
+            retry_after: None,
         }
     }
 
@@ -43,6 +47,26 @@ impl Error {
         Error {
             status_code: StatusCode::INTERNAL_SERVER_ERROR,
             message: format!("Internal server error: {:?}", e),
+            retry_after: None,
+        }
+    }
+
+    /// A prediction which timed out or was rejected as overloaded is reported as such,
+    /// any other failure as internal.
+    pub fn prediction_error(e: MlError) -> Self {
+        match e {
+            MlError::Timeout(message) => Error {
+                status_code: StatusCode::GATEWAY_TIMEOUT,
+                message: format!("Prediction timed out: {}", message),
+                retry_after: None,
+            },
+            MlError::Overloaded(overload) => Error {
+                status_code: StatusCode::SERVICE_UNAVAILABLE,
+                message: format!("Service unavailable: {}", overload.message),
+                // Retry-After is given in whole seconds, rounded up
+                retry_after: Some(overload.retry_after_ms.div_ceil(1000).max(1)),
+            },
+            e => Self::internal_server_error(e),
         }
     }
 
@@ -50,6 +74,7 @@ impl Error {
         Error {
             status_code: StatusCode::NOT_FOUND,
             message: "Object not found".to_string(),
+            retry_after: None,
         }
     }
 }
@@ -81,7 +106,14 @@ pub fn send_positive_response(response_out: ResponseOutparam, content: &str) {
 pub fn send_response_error(response_out: ResponseOutparam, error: Error) {
     log(Level::Error, "Api", &format!("Failed to process request: {:?}", error));
     
-    let response = OutgoingResponse::new(Fields::new());
+    let headers = Fields::new();
+    if let Some(seconds) = error.retry_after {
+        headers
+            .append(&"retry-after".to_string(), &seconds.to_string().into_bytes())
+            .expect("Unable to set Retry-After header");
+    }
+
+    let response = OutgoingResponse::new(headers);
     response
         .set_status_code(error.status_code.as_u16())
         .expect("Unable to set status code");
@@ -150,6 +182,7 @@ impl std::str::FromStr for DataType {
             _ => Err(Error {
                     status_code: StatusCode::BAD_REQUEST,
                     message: format!("Provided tensor's dtype is invalid"),
+                    retry_after: None,
                 }),
         }
     }
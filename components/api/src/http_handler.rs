@@ -1,5 +1,5 @@
 use urlencoding::decode; 
-use serde_json::from_str; 
+use serde_json::{from_str, json};
 use std::io::{Read, Write};
 use crate::wasi::logging::logging::*;
 use crate::streams::InputStreamReader;
@@ -13,8 +13,8 @@ use crate::errors::{Result, Error, send_positive_response, send_response_error};
 use crate::{
     wasi::http::types::*,
     wamli::ml::{
-        types::{DataType, Tensor},
-        inference::{prefetch, preempt, predict},
+        types::{DataType, LoadState, ModelSummary, PredictOptions, Tensor},
+        inference::{list_models, model_status, prefetch, preempt, predict, set_alias},
         conversion::convert,
         classification::classify,
     },
@@ -23,6 +23,7 @@ use crate::{
 #[allow(dead_code)]
 const DIMENSIONS_PARAM_NAME: &str = "dimensions";
 const VALUE_TYPE_PARAM_NAME: &str = "value_type";
+const TIMEOUT_PARAM_NAME: &str = "timeout_ms";
 // [1, 3, 244, 244] which is a typical ImageNet dimension
 const DEFAULT_DIMENIONS: &str = "%5B1%2C3%2C224%2C224%5D"; 
 const DEFAULT_VALUE_TYPE: &str = "NA";
@@ -49,9 +50,13 @@ impl HttpGuest for Api {
         // ex. 'localhost:8081/mobilenetv27:latest?dimensions=[2,2]&value_type=F32'
         // ex. 'localhost:8081/mobilenetv27%3Alatest?dimensions=%5B2%2C2%5D&value_type=F32'
         // ex. 'localhost:8081/no-preprocessing/mobilenetv27%3Alatest?dimensions=%5B2%2C2%5D&value_type=F32'
+        // ex. 'localhost:8081/mobilenetv27%3Alatest?timeout_ms=500'
         // ex. 'localhost:8081/prefetch/wamli-mobilenetv27%3Alatest?dimensions=%5B2%2C2%5D&value_type=F32'
         // ex. 'localhost:8081/prefetch/wamli-mobilenetv27%3Alatest'
-        let (full_path, dimensions, value_type) = match path_and_query.split_once('?') 
+        // ex. 'localhost:8081/models'
+        // ex. 'localhost:8081/models/wamli-mobilenetv27%3Alatest'
+        // ex. 'localhost:8081/alias/imagenet-classifier/wamli-mobilenetv27%3Alatest'
+        let (full_path, dimensions, value_type, timeout_ms) = match path_and_query.split_once('?') 
         {
             Some((path, query)) => {
                 // We have a query string, so let's split it into dimensions name and a value-type
@@ -69,17 +74,30 @@ impl HttpGuest for Api {
                     .find(|(k, _)| *k == VALUE_TYPE_PARAM_NAME)
                     .map(|(_, v)| v)
                     .unwrap_or(DEFAULT_VALUE_TYPE);
+
+                // the time the caller waits for a prediction at most
+                let timeout_ms = query
+                    .split('&')
+                    .filter_map(|p| p.split_once('='))
+                    .find(|(k, _)| *k == TIMEOUT_PARAM_NAME)
+                    .and_then(|(_, v)| v.parse::<u64>().ok());
                 
                 // (path.trim_matches('/').to_string(), dimensions.unwrap_or("").to_string(), value_type.unwrap_or("").to_string())
-                (path.trim_matches('/').to_string(), dimensions, value_type.to_string())
+                (path.trim_matches('/').to_string(), dimensions, value_type.to_string(), timeout_ms)
             }
             None => (
                 path_and_query.trim_matches('/').to_string(),
                 Some(DEFAULT_DIMENIONS),
                 DEFAULT_VALUE_TYPE.to_string(),
+                None,
             ),
         };
 
+        let options = PredictOptions {
+            routing_key: None,
+            timeout_ms,
+        };
+
         // dimensions.and_then(f);
 
         let dimensions_vector: Option<Vec<u32>> = dimensions.and_then(|d| {
@@ -99,6 +117,49 @@ impl HttpGuest for Api {
 
 
         match (method, segments.as_slice()) {
+            (Method::Get, ["models"]) => {
+                log(Level::Info, "Api", "--------> API: executing LIST-MODELS");
+
+                let models: Vec<serde_json::Value> = list_models()
+                    .iter()
+                    .map(model_summary_to_json)
+                    .collect();
+
+                send_positive_response(response_out, &serde_json::Value::Array(models).to_string());
+                return;
+            },
+
+            (Method::Get, ["models", model_id]) => {
+                log(Level::Info, "Api", &format!("--------> API: executing MODEL-STATUS with model_id: '{:?}' ", model_id));
+
+                let state = match model_status(model_id) {
+                    Ok(state) => state,
+                    Err(_) => {
+                        send_response_error(
+                            response_out,
+                            Error::not_found(),
+                        );
+                        return;
+                    },
+                };
+
+                let (state, error) = load_state_to_json(&state);
+
+                send_positive_response(response_out, &json!({ "model_id": model_id, "state": state, "error": error }).to_string());
+                return;
+            },
+
+            (Method::Put, ["alias", alias, model_id]) => {
+                log(Level::Info, "Api", &format!("--------> API: executing SET-ALIAS '{:?}' with model_id: '{:?}' ", alias, model_id));
+
+                let alias_result = set_alias(alias, model_id);
+
+                log(Level::Info, "Api", &format!("--------> API: SET-ALIAS result: '{:?}' ", alias_result));
+
+                send_positive_response(response_out, &format!("Feedback from inference provider: {:?}", alias_result));
+                return;
+            },
+
             (Method::Delete, [model_id]) => {
                 log(Level::Info, "Api", &format!("--------> API: going to DELETE model '{:?}' ", model_id));
 
@@ -167,12 +228,12 @@ impl HttpGuest for Api {
                 
                 log(Level::Info, "Api", &format!("--------> CONVERSION of length '{}' bytes received from PRE-processor", converted.data.len()));
 
-                let prediction = match predict(model_id, &converted) {
+                let prediction = match predict(model_id, &converted, Some(&options)) {
                     Ok(t) => t,
                     Err(error) => {
                         send_response_error(
                             response_out,
-                            Error::internal_server_error(error),
+                            Error::prediction_error(error),
                         );
                         return;
                     },
@@ -315,7 +376,7 @@ impl HttpGuest for Api {
                     data: body,
                 };
 
-                let prediction = predict(model_id, &tensor);
+                let prediction = predict(model_id, &tensor, Some(&options));
                 log(Level::Info, "", &format!("-------> PREDICTION received: {:?}", prediction));
             },
 
@@ -365,6 +426,31 @@ impl HttpGuest for Api {
     }
 }
 
+/// Name of the load state and the reason of a failure, if any
+fn load_state_to_json(state: &LoadState) -> (&'static str, Option<&str>) {
+    match state {
+        LoadState::Pending => ("pending", None),
+        LoadState::Downloading => ("downloading", None),
+        LoadState::Compiling => ("compiling", None),
+        LoadState::Ready => ("ready", None),
+        LoadState::Failed(reason) => ("failed", Some(reason.as_str())),
+    }
+}
+
+fn model_summary_to_json(summary: &ModelSummary) -> serde_json::Value {
+    let (state, error) = load_state_to_json(&summary.state);
+
+    json!({
+        "model_id": summary.model_id,
+        "encoding": summary.encoding.as_ref().map(|e| format!("{:?}", e).to_lowercase()),
+        "execution_target": summary.execution_target.as_ref().map(|t| format!("{:?}", t).to_lowercase()),
+        "state": state,
+        "error": error,
+        "loaded_at": summary.loaded_at,
+        "last_used": summary.last_used,
+    })
+}
+
 fn parse_request_body(request: IncomingRequest) -> Result<Vec<u8>> {
     let body = match request.consume() {
         Ok(b) => Ok(b),
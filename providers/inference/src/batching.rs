@@ -0,0 +1,548 @@
+//! Dynamic batching of inference requests.
+//!
+//! Requests for the same model, which arrive within a short period of time,
+//! are stacked along the batch dimension, computed at once and split back to
+//! their respective callers. The batch dimension is the model's declared batch
+//! axis, the first axis of each tensor otherwise. Requests whose deadline
+//! passes while the batch is collected are left out of it.
+
+use crate::provider::InferenceProvider;
+use crate::workers::Admission;
+use crate::{DataType, MlError, NamedTensor, Tensor};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout_at;
+
+/// Number of requests which may be queued for a batcher
+const REQUEST_QUEUE_CAPACITY: usize = 1024;
+
+/// Batching configuration of a model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchConfig {
+    /// Maximum number of samples, i.e. the sum of the batch dimensions, per batch
+    pub max_batch_size: usize,
+
+    /// Maximum time to wait for further requests once a batch has been opened
+    pub max_wait: Duration,
+}
+
+pub type BatchResult = Result<Vec<NamedTensor>, MlError>;
+
+struct BatchRequest {
+    tensors: Vec<NamedTensor>,
+    /// axis of the tensors which holds the batch
+    batch_axis: usize,
+    /// place of the request in its model's queue, held until its batch is computed
+    admission: Admission,
+    deadline: Option<Instant>,
+    respond_to: oneshot::Sender<BatchResult>,
+}
+
+impl BatchRequest {
+    fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+/// Handle to a model's batching task, which stops once all handles are dropped
+#[derive(Clone)]
+pub struct Batcher {
+    requests: mpsc::Sender<BatchRequest>,
+}
+
+impl Batcher {
+    /// Spawns the task collecting and computing batches for given model.
+    pub fn spawn(provider: InferenceProvider, model_id: String, config: BatchConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(REQUEST_QUEUE_CAPACITY);
+
+        log::info!(
+            "starting batcher for model '{}' with {:?}",
+            &model_id,
+            &config
+        );
+
+        tokio::spawn(collect_batches(provider, model_id, config, receiver));
+
+        Self { requests: sender }
+    }
+
+    /// Queues the tensors of a single request and awaits its share of the batch's result.
+    /// The tensors are stacked along their `batch_axis` with those of other requests.
+    /// The request is not computed anymore once its `deadline` has passed. Its
+    /// `admission` is released once its batch is computed or the request dropped.
+    pub async fn submit(
+        &self,
+        tensors: Vec<NamedTensor>,
+        batch_axis: usize,
+        admission: Admission,
+        deadline: Option<Instant>,
+    ) -> BatchResult {
+        let (respond_to, response) = oneshot::channel();
+
+        self.requests
+            .send(BatchRequest {
+                tensors,
+                batch_axis,
+                admission,
+                deadline,
+                respond_to,
+            })
+            .await
+            .map_err(|_| MlError::Internal("batcher is not running anymore".to_string()))?;
+
+        response
+            .await
+            .map_err(|_| MlError::Internal("batcher dropped the request".to_string()))?
+    }
+}
+
+/// Collects requests until either the maximum batch size is reached or the
+/// maximum wait time has passed, then computes the batch in the background.
+async fn collect_batches(
+    provider: InferenceProvider,
+    model_id: String,
+    config: BatchConfig,
+    mut receiver: mpsc::Receiver<BatchRequest>,
+) {
+    // a request which did not fit into the previous batch opens the next one
+    let mut carry_over: Option<BatchRequest> = None;
+
+    loop {
+        let first = match carry_over.take() {
+            Some(request) => request,
+            None => match receiver.recv().await {
+                Some(request) => request,
+                None => break,
+            },
+        };
+
+        let deadline = Instant::now() + config.max_wait;
+        let mut samples = batch_size(&first.tensors, first.batch_axis);
+        let mut requests = vec![first];
+
+        while samples < config.max_batch_size {
+            match timeout_at(deadline.into(), receiver.recv()).await {
+                Ok(Some(request)) => {
+                    let size = batch_size(&request.tensors, request.batch_axis);
+                    if samples + size > config.max_batch_size {
+                        carry_over = Some(request);
+                        break;
+                    }
+                    samples += size;
+                    requests.push(request);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        log::debug!(
+            "collect_batches() - collected {} request(s) with {} sample(s) for model '{}'",
+            requests.len(),
+            samples,
+            &model_id
+        );
+
+        for group in group_compatible(requests) {
+            tokio::spawn(compute_batch(provider.clone(), model_id.clone(), group));
+        }
+    }
+
+    log::info!("batcher for model '{}' stopped", &model_id);
+}
+
+/// Stacks the requests' tensors, computes them at once and splits up the results.
+async fn compute_batch(provider: InferenceProvider, model_id: String, requests: Vec<BatchRequest>) {
+    let (expired, requests): (Vec<BatchRequest>, Vec<BatchRequest>) =
+        requests.into_iter().partition(BatchRequest::is_expired);
+
+    for request in expired {
+        let _ = request.respond_to.send(Err(MlError::Timeout(format!(
+            "Deadline of the request for model '{}' passed while the batch was collected",
+            &model_id
+        ))));
+    }
+
+    if requests.is_empty() {
+        return;
+    }
+
+    if requests.len() == 1 {
+        let request = requests.into_iter().next().unwrap();
+        let result = provider
+            .infer(
+                &model_id,
+                request.tensors,
+                vec![request.admission],
+                request.deadline,
+            )
+            .await;
+        let _ = request.respond_to.send(result);
+        return;
+    }
+
+    // the batch is worth computing as long as any of its requests awaits it
+    let deadline = requests
+        .iter()
+        .map(|r| r.deadline)
+        .collect::<Option<Vec<Instant>>>()
+        .and_then(|deadlines| deadlines.into_iter().max());
+
+    // requests are only grouped with others of the same batch axis
+    let batch_axis = requests[0].batch_axis;
+    let sizes: Vec<usize> = requests
+        .iter()
+        .map(|r| batch_size(&r.tensors, batch_axis))
+        .collect();
+
+    let mut tensors: Vec<Vec<NamedTensor>> = Vec::with_capacity(requests.len());
+    let mut admissions: Vec<Admission> = Vec::with_capacity(requests.len());
+    let mut senders: Vec<oneshot::Sender<BatchResult>> = Vec::with_capacity(requests.len());
+    for request in requests {
+        tensors.push(request.tensors);
+        admissions.push(request.admission);
+        senders.push(request.respond_to);
+    }
+
+    let results = match provider
+        .infer(&model_id, stack(tensors, batch_axis), admissions, deadline)
+        .await
+    {
+        Ok(tensors_out) => split(tensors_out, &sizes, batch_axis),
+        Err(error) => Err(error),
+    };
+
+    match results {
+        Ok(results) => {
+            for (sender, result) in senders.into_iter().zip(results) {
+                let _ = sender.send(Ok(result));
+            }
+        }
+        Err(error) => {
+            log::error!(
+                "compute_batch() - batch of model '{}' failed with {:?}",
+                &model_id,
+                &error
+            );
+            for sender in senders {
+                let _ = sender.send(Err(error.clone()));
+            }
+        }
+    }
+}
+
+/// Size of the batch dimension of a request, i.e. of its first tensor.
+fn batch_size(tensors: &[NamedTensor], batch_axis: usize) -> usize {
+    tensors
+        .first()
+        .and_then(|t| t.tensor.shape.get(batch_axis))
+        .map(|&dim| dim as usize)
+        .unwrap_or(1)
+}
+
+/// Batch axis along with name, data type and shape without batch dimension
+/// of each tensor of a request
+type StackingKey = (usize, Vec<(Option<String>, DataType, Vec<u32>)>);
+
+/// Requests can be stacked in case their tensors only differ in the batch dimension.
+fn stacking_key(tensors: &[NamedTensor], batch_axis: usize) -> StackingKey {
+    let tensors = tensors
+        .iter()
+        .map(|t| {
+            let mut shape = t.tensor.shape.clone();
+            shape.remove(batch_axis);
+            (t.name.clone(), t.tensor.dtype, shape)
+        })
+        .collect();
+
+    (batch_axis, tensors)
+}
+
+/// Groups the requests which can be stacked, preserving their order. Requests
+/// containing tensors without batch dimension, e.g. scalars, cannot be stacked
+/// and form a group of their own.
+fn group_compatible(requests: Vec<BatchRequest>) -> Vec<Vec<BatchRequest>> {
+    let mut groups: Vec<(Option<StackingKey>, Vec<BatchRequest>)> = Vec::new();
+
+    for request in requests {
+        let stackable = request
+            .tensors
+            .iter()
+            .all(|t| t.tensor.shape.len() > request.batch_axis);
+        let key = match stackable {
+            true => Some(stacking_key(&request.tensors, request.batch_axis)),
+            false => None,
+        };
+
+        match groups
+            .iter_mut()
+            .find(|(k, _)| key.is_some() && *k == key)
+        {
+            Some((_, group)) => group.push(request),
+            None => groups.push((key, vec![request])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Concatenates the requests' tensors along the batch dimension.
+fn stack(requests: Vec<Vec<NamedTensor>>, batch_axis: usize) -> Vec<NamedTensor> {
+    // the tensors of all requests by their position in the request
+    let mut columns: Vec<Vec<NamedTensor>> = Vec::new();
+
+    for tensors in requests {
+        for (index, t) in tensors.into_iter().enumerate() {
+            match columns.get_mut(index) {
+                Some(column) => column.push(t),
+                None => columns.push(vec![t]),
+            }
+        }
+    }
+
+    columns
+        .into_iter()
+        .map(|column| concatenate(column, batch_axis))
+        .collect()
+}
+
+/// Concatenates tensors, which only differ in the batch dimension, along it.
+/// Each tensor consists of a block of data per index of the axes in front
+/// of the batch axis, the tensors' blocks are interleaved one after another.
+fn concatenate(tensors: Vec<NamedTensor>, batch_axis: usize) -> NamedTensor {
+    let blocks: usize = tensors[0].tensor.shape[..batch_axis]
+        .iter()
+        .map(|&dim| dim as usize)
+        .product();
+
+    let mut data = Vec::with_capacity(tensors.iter().map(|t| t.tensor.data.len()).sum());
+    for block in 0..blocks {
+        for t in &tensors {
+            let block_len = t.tensor.data.len() / blocks;
+            data.extend_from_slice(&t.tensor.data[block * block_len..(block + 1) * block_len]);
+        }
+    }
+
+    let samples: u32 = tensors.iter().map(|t| t.tensor.shape[batch_axis]).sum();
+
+    let mut stacked = tensors.into_iter().next().unwrap();
+    stacked.tensor.shape[batch_axis] = samples;
+    stacked.tensor.data = data.into();
+
+    stacked
+}
+
+/// Splits each of the batch's output tensors along the batch dimension
+/// into chunks of given sizes, one chunk per request.
+fn split(
+    tensors: Vec<NamedTensor>,
+    sizes: &[usize],
+    batch_axis: usize,
+) -> Result<Vec<Vec<NamedTensor>>, MlError> {
+    let total: usize = sizes.iter().sum();
+    let mut results: Vec<Vec<NamedTensor>> = sizes.iter().map(|_| Vec::new()).collect();
+
+    for t in tensors {
+        if t.tensor.shape.get(batch_axis).map(|&dim| dim as usize) != Some(total) {
+            return Err(MlError::RuntimeError(format!(
+                "output {:?} of shape {:?} cannot be split into batches of sizes {:?}",
+                t.name, t.tensor.shape, sizes
+            )));
+        }
+
+        // one block of samples per index of the axes in front of the batch axis
+        let blocks: usize = t.tensor.shape[..batch_axis]
+            .iter()
+            .map(|&dim| dim as usize)
+            .product();
+
+        // a batch without any sample leaves each request an empty share
+        let bytes_per_sample = match blocks * total {
+            0 => 0,
+            samples => t.tensor.data.len() / samples,
+        };
+        let block_len = total * bytes_per_sample;
+        let mut offset = 0;
+
+        for (result, &size) in results.iter_mut().zip(sizes) {
+            let mut shape = t.tensor.shape.clone();
+            shape[batch_axis] = size as u32;
+
+            let len = size * bytes_per_sample;
+            let mut data = Vec::with_capacity(blocks * len);
+            for block in 0..blocks {
+                let start = block * block_len + offset;
+                data.extend_from_slice(&t.tensor.data[start..start + len]);
+            }
+
+            result.push(NamedTensor {
+                name: t.name.clone(),
+                tensor: Tensor {
+                    shape,
+                    dtype: t.tensor.dtype,
+                    data: data.into(),
+                },
+            });
+
+            offset += len;
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str, shape: Vec<u32>, data: Vec<u8>) -> NamedTensor {
+        NamedTensor {
+            name: Some(name.to_string()),
+            tensor: Tensor {
+                shape,
+                dtype: DataType::U8,
+                data: data.into(),
+            },
+        }
+    }
+
+    fn request(tensors: Vec<NamedTensor>, batch_axis: usize) -> BatchRequest {
+        BatchRequest {
+            tensors,
+            batch_axis,
+            admission: Admission::detached("model"),
+            deadline: None,
+            respond_to: oneshot::channel().0,
+        }
+    }
+
+    #[test]
+    fn stack_concatenates_along_the_batch_dimension() {
+        let stacked = stack(
+            vec![
+                vec![named("x", vec![1, 2], vec![1, 2]), named("y", vec![1], vec![7])],
+                vec![named("x", vec![2, 2], vec![3, 4, 5, 6]), named("y", vec![2], vec![8, 9])],
+            ],
+            0,
+        );
+
+        assert_eq!(stacked.len(), 2);
+        assert_eq!(stacked[0].name.as_deref(), Some("x"));
+        assert_eq!(stacked[0].tensor.shape, vec![3, 2]);
+        assert_eq!(&stacked[0].tensor.data[..], &[1, 2, 3, 4, 5, 6][..]);
+        assert_eq!(stacked[1].name.as_deref(), Some("y"));
+        assert_eq!(stacked[1].tensor.shape, vec![3]);
+        assert_eq!(&stacked[1].tensor.data[..], &[7, 8, 9][..]);
+    }
+
+    #[test]
+    fn split_returns_each_request_its_share() {
+        let output = named("out", vec![3, 2], vec![1, 2, 3, 4, 5, 6]);
+        let results = split(vec![output], &[1, 2], 0).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0][0].tensor.shape, vec![1, 2]);
+        assert_eq!(&results[0][0].tensor.data[..], &[1, 2][..]);
+        assert_eq!(results[1][0].tensor.shape, vec![2, 2]);
+        assert_eq!(&results[1][0].tensor.data[..], &[3, 4, 5, 6][..]);
+    }
+
+    #[test]
+    fn split_rejects_outputs_of_another_batch_size() {
+        let result = split(vec![named("out", vec![2, 2], vec![1, 2, 3, 4])], &[1, 2], 0);
+
+        assert!(matches!(result, Err(MlError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn split_leaves_empty_shares_of_an_empty_batch() {
+        let results = split(vec![named("out", vec![0, 2], vec![])], &[0, 0], 0).unwrap();
+
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result[0].tensor.shape, vec![0, 2]);
+            assert!(result[0].tensor.data.is_empty());
+        }
+    }
+
+    #[test]
+    fn group_compatible_groups_requests_which_only_differ_in_batch_size() {
+        let groups = group_compatible(vec![
+            request(vec![named("x", vec![1, 2], vec![0; 2])], 0),
+            request(vec![named("x", vec![3, 2], vec![0; 6])], 0),
+            request(vec![named("x", vec![1, 3], vec![0; 3])], 0),
+            request(vec![named("z", vec![1, 2], vec![0; 2])], 0),
+            request(vec![named("x", vec![2, 2], vec![0; 4])], 0),
+        ]);
+
+        let sizes: Vec<Vec<usize>> = groups
+            .iter()
+            .map(|group| group.iter().map(|r| batch_size(&r.tensors, 0)).collect())
+            .collect();
+
+        assert_eq!(sizes, vec![vec![1, 3, 2], vec![1], vec![1]]);
+        assert_eq!(groups[1][0].tensors[0].tensor.shape, vec![1, 3]);
+        assert_eq!(groups[2][0].tensors[0].name.as_deref(), Some("z"));
+    }
+
+    #[test]
+    fn group_compatible_never_stacks_scalars() {
+        let groups = group_compatible(vec![
+            request(vec![named("x", vec![], vec![1])], 0),
+            request(vec![named("x", vec![], vec![2])], 0),
+        ]);
+
+        assert_eq!(groups.len(), 2);
+    }
+
+    #[test]
+    fn stack_concatenates_along_a_declared_batch_axis() {
+        let stacked = stack(
+            vec![
+                vec![named("x", vec![2, 1, 2], vec![1, 2, 3, 4])],
+                vec![named("x", vec![2, 2, 2], vec![5, 6, 7, 8, 9, 10, 11, 12])],
+            ],
+            1,
+        );
+
+        assert_eq!(stacked[0].tensor.shape, vec![2, 3, 2]);
+        assert_eq!(
+            &stacked[0].tensor.data[..],
+            &[1, 2, 5, 6, 7, 8, 3, 4, 9, 10, 11, 12][..]
+        );
+    }
+
+    #[test]
+    fn split_divides_along_a_declared_batch_axis() {
+        let output = named("out", vec![2, 3, 2], vec![1, 2, 5, 6, 7, 8, 3, 4, 9, 10, 11, 12]);
+        let results = split(vec![output], &[1, 2], 1).unwrap();
+
+        assert_eq!(results[0][0].tensor.shape, vec![2, 1, 2]);
+        assert_eq!(&results[0][0].tensor.data[..], &[1, 2, 3, 4][..]);
+        assert_eq!(results[1][0].tensor.shape, vec![2, 2, 2]);
+        assert_eq!(&results[1][0].tensor.data[..], &[5, 6, 7, 8, 9, 10, 11, 12][..]);
+    }
+
+    #[test]
+    fn group_compatible_never_stacks_tensors_without_batch_axis() {
+        let groups = group_compatible(vec![
+            request(vec![named("x", vec![1, 2], vec![0; 2])], 1),
+            request(vec![named("x", vec![1, 3], vec![0; 3])], 1),
+            request(vec![named("x", vec![2], vec![0; 2])], 1),
+            request(vec![named("x", vec![2], vec![0; 2])], 1),
+            request(vec![named("x", vec![1, 2], vec![0; 2])], 0),
+        ]);
+
+        let sizes: Vec<Vec<usize>> = groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|r| batch_size(&r.tensors, r.batch_axis))
+                    .collect()
+            })
+            .collect();
+
+        // requests of another batch axis are not stacked either
+        assert_eq!(sizes, vec![vec![2, 3], vec![1], vec![1], vec![1]]);
+    }
+}
@@ -0,0 +1,292 @@
+//! On-disk cache of compiled models.
+//!
+//! Once a model is registered, its engine compiles it for the input declared by
+//! the model's metadata and serializes it as an NNEF archive. The archive is
+//! cached by the digest of the model's content along with the input facts it
+//! was compiled for, see `CacheKey`. An index file maps each image to the key
+//! of its compilation, along with the digest of the image's manifest. On the next
+//! start, the model is loaded from its archive, which skips pulling the model as
+//! well as importing it from its original encoding. Only the manifest's digest is
+//! requested from the registry, such that a tag moved to another image misses the
+//! cache. While the registry is unreachable, the index is relied upon.
+
+use crate::data_loader::{ModelMetadata, ModelRawData};
+use crate::engine::GraphEncoding;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// File name of the index, which maps images to the keys of their compilations
+const INDEX_FILE: &str = "index.json";
+
+/// Compilation of an image as recorded by the index
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct IndexEntry {
+    /// digest of the image's manifest at the time it was compiled, if known
+    manifest_digest: Option<String>,
+    key: CacheKey,
+}
+
+/// Serializes updates of the index, which is rewritten as a whole
+static INDEX_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Identifies the compilation of a model
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    /// digest of the model's content, see `ModelRawData`
+    pub digest: String,
+    /// input facts the model is compiled for, e.g. `f32-nx3x224x224`,
+    /// where `n` stands for the symbolic batch dimension
+    pub input_facts: String,
+}
+
+impl CacheKey {
+    /// Key of the model's compilation for the input declared by its metadata
+    pub fn new(digest: &str, metadata: &ModelMetadata) -> Self {
+        // only models of known data types are registered, hence cached
+        let dtype = metadata.tensor_dtype.to_lowercase();
+        let shape = match (&metadata.tensor_shape_in, metadata.batch_axis) {
+            (Some(shape), batch_axis) => shape
+                .iter()
+                .enumerate()
+                .map(|(axis, dim)| match batch_axis == Some(axis) {
+                    true => "n".to_string(),
+                    false => dim.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join("x"),
+            (None, Some(batch_axis)) => format!("declared-n{}", batch_axis),
+            (None, None) => "declared".to_string(),
+        };
+
+        Self {
+            digest: digest.to_owned(),
+            input_facts: format!("{}-{}", dtype, shape),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileCache {
+    dir: PathBuf,
+}
+
+impl CompileCache {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Directory of all compilations of the model with given digest
+    fn model_dir(&self, digest: &str) -> PathBuf {
+        // digests look like `sha256:<hex>`, colons are no valid file names everywhere
+        self.dir.join(digest.replace(':', "-"))
+    }
+
+    /// Files of the compilation's archive and of the model's metadata
+    fn compilation_files(&self, key: &CacheKey) -> (PathBuf, PathBuf) {
+        let model_dir = self.model_dir(&key.digest);
+
+        (
+            model_dir.join(format!("{}.nnef.tar", key.input_facts)),
+            model_dir.join(format!("{}.json", key.input_facts)),
+        )
+    }
+
+    /// Key of the image's compilation as cached before, `None` if there is none or
+    /// the image's current `manifest_digest` differs from the compiled one's. Without
+    /// the current digest, e.g. while the registry is unreachable, any compilation goes.
+    pub async fn lookup(&self, image: &str, manifest_digest: Option<&str>) -> Option<CacheKey> {
+        let entry = self.read_index().await.remove(image)?;
+
+        match manifest_digest {
+            Some(current) if entry.manifest_digest.as_deref() != Some(current) => {
+                log::info!(
+                    "image '{}' is now {}, its cached compilation is outdated",
+                    image,
+                    current
+                );
+                None
+            }
+            _ => Some(entry.key),
+        }
+    }
+
+    /// Loads the compilation with given key, `None` on a cache miss.
+    /// The model is loaded as `GraphEncoding::Nnef`, regardless of its original encoding.
+    pub async fn load(&self, key: &CacheKey) -> Option<ModelRawData> {
+        let (file, metadata_file) = self.compilation_files(key);
+
+        let metadata = tokio::fs::read(&metadata_file).await.ok()?;
+        let mut metadata = match ModelMetadata::from_rawdata(&metadata).await {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                log::warn!("load() - ignoring cached metadata {:?}: {}", metadata_file, error);
+                return None;
+            }
+        };
+
+        let model = tokio::fs::read(&file).await.ok()?;
+
+        log::debug!("load() - compiled model found at {:?}", file);
+
+        metadata.graph_encoding = "nnef".to_string();
+
+        Some(ModelRawData {
+            digest: key.digest.clone(),
+            model,
+            metadata,
+        })
+    }
+
+    /// Stores the compilation of the image's model with given digest and metadata,
+    /// along with the `manifest_digest` of the image, if known. Failures are
+    /// logged only, since the model is available anyway.
+    pub async fn store(
+        &self,
+        image: &str,
+        manifest_digest: Option<&str>,
+        digest: &str,
+        metadata: &ModelMetadata,
+        compiled: &[u8],
+    ) {
+        let written = self
+            .write(image, manifest_digest, digest, metadata, compiled)
+            .await;
+
+        if let Err(error) = written {
+            log::warn!(
+                "store() - failed to cache compiled model '{}' in {:?}: {}",
+                image,
+                self.dir,
+                error
+            );
+        }
+    }
+
+    async fn write(
+        &self,
+        image: &str,
+        manifest_digest: Option<&str>,
+        digest: &str,
+        metadata: &ModelMetadata,
+        compiled: &[u8],
+    ) -> std::io::Result<()> {
+        let key = CacheKey::new(digest, metadata);
+        let (file, metadata_file) = self.compilation_files(&key);
+
+        tokio::fs::create_dir_all(self.model_dir(digest)).await?;
+
+        tokio::fs::write(&metadata_file, serde_json::to_vec(metadata)?).await?;
+        write_atomically(&file, compiled).await?;
+
+        // the index is updated last, such that it never refers to a missing archive
+        let _guard = INDEX_LOCK.lock().await;
+        let mut index = self.read_index().await;
+        index.insert(
+            image.to_owned(),
+            IndexEntry {
+                manifest_digest: manifest_digest.map(str::to_owned),
+                key,
+            },
+        );
+        write_atomically(&self.dir.join(INDEX_FILE), &serde_json::to_vec_pretty(&index)?).await?;
+
+        log::info!("cached compiled model '{}' at {:?}", image, file);
+
+        Ok(())
+    }
+
+    /// Cached compilations by image, empty if nothing is cached yet
+    async fn read_index(&self) -> BTreeMap<String, IndexEntry> {
+        let index = match tokio::fs::read(self.dir.join(INDEX_FILE)).await {
+            Ok(index) => index,
+            Err(_) => return BTreeMap::new(),
+        };
+
+        serde_json::from_slice(&index).unwrap_or_else(|error| {
+            log::warn!("read_index() - ignoring corrupt index in {:?}: {}", self.dir, error);
+            BTreeMap::new()
+        })
+    }
+}
+
+/// Writes the file aside and moves it into place, such that a crash
+/// in between never leaves a truncated file behind
+async fn write_atomically(file: &Path, data: &[u8]) -> std::io::Result<()> {
+    let partial = file.with_extension("partial");
+    tokio::fs::write(&partial, data).await?;
+    tokio::fs::rename(&partial, file).await
+}
+
+/// Whether models of given encoding are worth caching. NNEF models already
+/// load without any import, which includes the cached models themselves.
+pub fn is_cacheable(encoding: &GraphEncoding) -> bool {
+    !matches!(encoding, GraphEncoding::Nnef)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(shape: Option<Vec<u32>>, batch_axis: Option<usize>) -> ModelMetadata {
+        ModelMetadata {
+            model_name: None,
+            graph_encoding: "onnx".to_string(),
+            execution_target: "cpu".to_string(),
+            tensor_dtype: "F32".to_string(),
+            tensor_shape_in: shape,
+            tensor_shape_out: None,
+            batch_axis,
+        }
+    }
+
+    #[test]
+    fn key_holds_the_input_facts() {
+        let key = CacheKey::new("sha256:abc", &metadata(Some(vec![1, 3, 224, 224]), None));
+        assert_eq!(key.digest, "sha256:abc");
+        assert_eq!(key.input_facts, "f32-1x3x224x224");
+
+        let key = CacheKey::new("sha256:abc", &metadata(Some(vec![1, 3, 224, 224]), Some(0)));
+        assert_eq!(key.input_facts, "f32-nx3x224x224");
+
+        let key = CacheKey::new("sha256:abc", &metadata(None, None));
+        assert_eq!(key.input_facts, "f32-declared");
+    }
+
+    #[tokio::test]
+    async fn stored_compilations_are_found_through_the_index() {
+        let dir = std::env::temp_dir().join(format!("compile-cache-{}", std::process::id()));
+        let cache = CompileCache::new(&dir);
+        let metadata = metadata(Some(vec![1, 4]), Some(0));
+
+        let image = "localhost:5000/model:v1";
+        assert_eq!(cache.lookup(image, None).await, None);
+
+        cache
+            .store(image, Some("sha256:m1"), "sha256:abc", &metadata, b"archive")
+            .await;
+
+        let key = cache.lookup(image, Some("sha256:m1")).await.unwrap();
+        assert_eq!(key, CacheKey::new("sha256:abc", &metadata));
+
+        // the index is relied upon while the registry is unreachable
+        assert_eq!(cache.lookup(image, None).await, Some(key.clone()));
+
+        // the tag moved to another image
+        assert_eq!(cache.lookup(image, Some("sha256:m2")).await, None);
+
+        let model_data = cache.load(&key).await.unwrap();
+        assert_eq!(model_data.digest, "sha256:abc");
+        assert_eq!(model_data.model, b"archive");
+        assert_eq!(model_data.metadata.graph_encoding, "nnef");
+
+        // another batch axis is another compilation
+        let other = CacheKey::new("sha256:abc", &ModelMetadata { batch_axis: None, ..metadata });
+        assert!(cache.load(&other).await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
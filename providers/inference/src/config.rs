@@ -1,5 +1,8 @@
+use crate::batching::BatchConfig;
+use crate::compile_cache::CompileCache;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
 /// Default URL to use to connect to registry
 pub const DEFAULT_CONNECT_URL: &str = "localhost:5000";
@@ -7,7 +10,90 @@ pub const DEFAULT_CONNECT_URL: &str = "localhost:5000";
 /// Configuration key that will be used to search for config url
 pub const CONFIG_URL_KEY: &str = "URL";
 
+/// Configuration key of the maximum number of models held in memory at the same time
+pub const CONFIG_MAX_MODELS_KEY: &str = "max_models";
+
+/// Configuration key of the memory budget in megabytes for all models held in memory
+pub const CONFIG_MEMORY_BUDGET_KEY: &str = "memory_budget_mb";
+
+/// Configuration key to enable loading unknown models on their first prediction
+pub const CONFIG_AUTOLOAD_KEY: &str = "autoload";
+
+/// Configuration key of the directory of the compile cache, which is disabled if not set
+pub const CONFIG_COMPILE_CACHE_KEY: &str = "compile_cache_dir";
+
+/// Configuration key of the number of threads running inferences,
+/// defaults to the number of available CPUs
+pub const CONFIG_INFERENCE_WORKERS_KEY: &str = "inference_workers";
+
+/// Provider wide settings, i.e. keys which do not refer to a model
+const PROVIDER_SETTINGS: [&str; 6] = [
+    CONFIG_URL_KEY,
+    CONFIG_MAX_MODELS_KEY,
+    CONFIG_MEMORY_BUDGET_KEY,
+    CONFIG_AUTOLOAD_KEY,
+    CONFIG_COMPILE_CACHE_KEY,
+    CONFIG_INFERENCE_WORKERS_KEY,
+];
+
+/// Prefix of the configuration keys which point an alias at a model,
+/// e.g. `alias.imagenet-classifier` with value `wamli-mobilenetv27:latest`
+pub const CONFIG_ALIAS_PREFIX: &str = "alias.";
+
+/// Model specific setting: a pinned model is never evicted to make room for others
+pub const CONFIG_PINNED_KEY: &str = "pinned";
+
+/// Model specific setting: id of a shadow model, which receives copies
+/// of the model's requests such that their outputs can be compared
+pub const CONFIG_SHADOW_KEY: &str = "shadow";
+
+/// Model specific setting: maximum number of samples per batch.
+/// Batching is enabled for a model if this is larger than 1.
+pub const CONFIG_MAX_BATCH_SIZE_KEY: &str = "max_batch_size";
+
+/// Model specific setting: maximum time in milliseconds to wait for further requests
+pub const CONFIG_MAX_BATCH_WAIT_KEY: &str = "max_batch_wait_ms";
+
+/// Default maximum time in milliseconds to wait for further requests
+pub const DEFAULT_MAX_BATCH_WAIT_MS: u64 = 5;
+
+/// Model specific setting: maximum number of the model's inferences running at the same time
+pub const CONFIG_MAX_CONCURRENCY_KEY: &str = "max_concurrency";
+
+/// Model specific setting: maximum number of the model's requests queued or running
+/// at the same time. Further requests are rejected as overloaded.
+pub const CONFIG_MAX_QUEUE_DEPTH_KEY: &str = "max_queue_depth";
+
+/// Default maximum number of a model's requests queued or running at the same time
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 64;
+
+/// Model specific setting: time in milliseconds a request may take at most,
+/// including the time it is queued. Requests are not limited if not set.
+pub const CONFIG_TIMEOUT_KEY: &str = "timeout_ms";
+
+/// Limits of the models held in memory at the same time, unlimited if `None`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelLimits {
+    pub max_models: Option<usize>,
+    /// in bytes
+    pub memory_budget: Option<usize>,
+}
+
+impl ModelLimits {
+    /// Whether `count` models of `size` bytes in total exceed the limits
+    pub fn exceeded_by(&self, count: usize, size: usize) -> bool {
+        self.max_models.is_some_and(|max| count > max)
+            || self.memory_budget.is_some_and(|budget| size > budget)
+    }
+}
+
 /// Configuration for this provider, which is passed to the provider from the host.
+///
+/// Besides the registry's url and the limits of the models held in memory,
+/// each entry is supposed to map a key to a model,
+/// e.g. `mobilenetv27: wamli-mobilenetv27:latest`. Settings of a model are
+/// configured as `<key>.<setting>`, where `<key>` is either the model's
+/// configuration key or its id, e.g. `mobilenetv27.max_batch_size: 8`.
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ProviderConfig {
     pub values: HashMap<String, String>,
@@ -24,3 +110,158 @@ impl From<&HashMap<String, String>> for ProviderConfig {
         }
     }
 }
+
+impl ProviderConfig {
+    /// Value of a provider wide setting, the key is matched case-insensitively.
+    pub fn setting(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Models to be loaded at startup as pairs of configuration key and model id.
+    pub fn models(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .filter(|(k, _)| {
+                !PROVIDER_SETTINGS.iter().any(|s| k.eq_ignore_ascii_case(s)) && !k.contains('.')
+            })
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Aliases to be pointed at their models at startup as pairs of alias and model id.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(CONFIG_ALIAS_PREFIX)?, v.as_str())))
+            .filter(|(alias, _)| !alias.is_empty())
+    }
+
+    /// Value of a model specific setting.
+    pub fn model_setting(&self, model_id: &str, setting: &str) -> Option<&str> {
+        let by_id = format!("{}.{}", model_id, setting);
+
+        if let Some(value) = self.values.get(&by_id) {
+            return Some(value.as_str());
+        }
+
+        self.models()
+            .filter(|(_, id)| *id == model_id)
+            .find_map(|(key, _)| self.values.get(&format!("{}.{}", key, setting)))
+            .map(|v| v.as_str())
+    }
+
+    /// Limits of the models held in memory, see `ModelLimits`
+    pub fn model_limits(&self) -> ModelLimits {
+        ModelLimits {
+            max_models: self
+                .setting(CONFIG_MAX_MODELS_KEY)
+                .and_then(|v| parse_setting::<usize>("provider", CONFIG_MAX_MODELS_KEY, v)),
+            memory_budget: self
+                .setting(CONFIG_MEMORY_BUDGET_KEY)
+                .and_then(|v| parse_setting::<usize>("provider", CONFIG_MEMORY_BUDGET_KEY, v))
+                .map(|megabytes| megabytes * 1024 * 1024),
+        }
+    }
+
+    /// Whether unknown models are loaded on their first prediction
+    pub fn autoload(&self) -> bool {
+        self.setting(CONFIG_AUTOLOAD_KEY)
+            .and_then(|v| parse_setting::<bool>("provider", CONFIG_AUTOLOAD_KEY, v))
+            .unwrap_or(false)
+    }
+
+    /// The compile cache, if configured
+    pub fn compile_cache(&self) -> Option<CompileCache> {
+        self.setting(CONFIG_COMPILE_CACHE_KEY)
+            .map(str::trim)
+            .filter(|dir| !dir.is_empty())
+            .map(CompileCache::new)
+    }
+
+    /// Number of threads running inferences
+    pub fn inference_workers(&self) -> usize {
+        self.setting(CONFIG_INFERENCE_WORKERS_KEY)
+            .and_then(|v| parse_setting::<usize>("provider", CONFIG_INFERENCE_WORKERS_KEY, v))
+            .filter(|&workers| workers > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// Maximum number of the model's inferences running at the same time, unlimited if `None`
+    pub fn max_concurrency(&self, model_id: &str) -> Option<usize> {
+        self.model_setting(model_id, CONFIG_MAX_CONCURRENCY_KEY)
+            .and_then(|v| parse_setting::<usize>(model_id, CONFIG_MAX_CONCURRENCY_KEY, v))
+            .filter(|&max| max > 0)
+    }
+
+    /// Maximum number of the model's requests queued or running at the same time
+    pub fn max_queue_depth(&self, model_id: &str) -> usize {
+        self.model_setting(model_id, CONFIG_MAX_QUEUE_DEPTH_KEY)
+            .and_then(|v| parse_setting::<usize>(model_id, CONFIG_MAX_QUEUE_DEPTH_KEY, v))
+            .filter(|&depth| depth > 0)
+            .unwrap_or(DEFAULT_MAX_QUEUE_DEPTH)
+    }
+
+    /// Time a request for the model may take at most, unlimited if `None`
+    pub fn timeout(&self, model_id: &str) -> Option<Duration> {
+        self.model_setting(model_id, CONFIG_TIMEOUT_KEY)
+            .and_then(|v| parse_setting::<u64>(model_id, CONFIG_TIMEOUT_KEY, v))
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis)
+    }
+
+    /// Whether the model must never be evicted
+    pub fn is_pinned(&self, model_id: &str) -> bool {
+        self.model_setting(model_id, CONFIG_PINNED_KEY)
+            .and_then(|v| parse_setting::<bool>(model_id, CONFIG_PINNED_KEY, v))
+            .unwrap_or(false)
+    }
+
+    /// Id of the model's shadow model, if any
+    pub fn shadow_model(&self, model_id: &str) -> Option<&str> {
+        self.model_setting(model_id, CONFIG_SHADOW_KEY)
+            .map(str::trim)
+            .filter(|shadow_model_id| !shadow_model_id.is_empty() && *shadow_model_id != model_id)
+    }
+
+    /// Batching configuration of a model, if batching is enabled for it.
+    pub fn batch_config(&self, model_id: &str) -> Option<BatchConfig> {
+        let max_batch_size = parse_setting::<usize>(
+            model_id,
+            CONFIG_MAX_BATCH_SIZE_KEY,
+            self.model_setting(model_id, CONFIG_MAX_BATCH_SIZE_KEY)?,
+        )?;
+
+        if max_batch_size <= 1 {
+            return None;
+        }
+
+        let max_wait_ms = self
+            .model_setting(model_id, CONFIG_MAX_BATCH_WAIT_KEY)
+            .and_then(|v| parse_setting::<u64>(model_id, CONFIG_MAX_BATCH_WAIT_KEY, v))
+            .unwrap_or(DEFAULT_MAX_BATCH_WAIT_MS);
+
+        Some(BatchConfig {
+            max_batch_size,
+            max_wait: Duration::from_millis(max_wait_ms),
+        })
+    }
+}
+
+/// Parses a setting's value, invalid values are logged and ignored.
+/// `owner` is the model the setting belongs to, or the provider.
+fn parse_setting<T: std::str::FromStr>(owner: &str, setting: &str, value: &str) -> Option<T> {
+    match value.trim().parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            log::warn!(
+                "ignoring invalid value '{}' of setting '{}' for '{}'",
+                value,
+                setting,
+                owner
+            );
+            None
+        }
+    }
+}
@@ -1,4 +1,3 @@
-use log::error;
 use std::io::Read;
 
 #[allow(dead_code)]
@@ -13,12 +12,14 @@ pub use crate::data_loader::model_loader::ModelMetadata;
 pub const MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
 
 pub struct ModelRawData {
+    /// Digest of the image layer, which identifies the model's content
+    pub digest: String,
     pub model: Vec<u8>,
     pub metadata: model_loader::ModelMetadata,
 }
 
 pub async fn fetch_model(registry: &str, image_ref: &str) -> DataLoaderResult<ModelRawData> {
-    let oci_image = registry.to_owned() + "/" + &image_ref;
+    let oci_image = registry.to_owned() + "/" + image_ref;
 
     info!(
         "executing PREFETCH with registry '{}', model '{}' and image '{}'",
@@ -36,13 +37,19 @@ pub async fn fetch_model(registry: &str, image_ref: &str) -> DataLoaderResult<Mo
     Ok(model_data)
 }
 
+/// Digest of the image's manifest, which changes whenever its tag is moved.
+/// Only the manifest's headers are requested from the registry.
+pub async fn fetch_manifest_digest(image_ref: &str) -> DataLoaderResult<String> {
+    oci_image_loader::fetch_manifest_digest(image_ref).await
+}
+
 pub async fn pull_model_and_metadata(
     image_ref: &str,
     content_type: &str,
 ) -> DataLoaderResult<ModelRawData> {
     let oci_image = oci_image_loader::pull_image(image_ref, content_type).await?;
 
-    let first_layer = oci_image_loader::read_first_layer(oci_image).await?;
+    let (digest, first_layer) = oci_image_loader::read_first_layer(oci_image).await?;
 
     let uncompressed_layer = oci_image_loader::uncompress_layer(first_layer).await?;
 
@@ -56,8 +63,9 @@ pub async fn pull_model_and_metadata(
     let metadata = ModelMetadata::from_rawdata(&meta_rawdata).await?;
 
     Ok(ModelRawData {
-        model: model,
-        metadata: metadata,
+        digest,
+        model,
+        metadata,
     })
 }
 
@@ -28,13 +28,19 @@ pub struct ModelMetadata {
 
     /// tensor dimensions in (optional)
     /// Each model expects a specific shape of input tensors.
-    /// However, the shape is currently not processed any further.
+    /// Input tensors are validated against it, as far as the graph leaves it open.
     #[serde(default)]
     pub tensor_shape_in: Option<Vec<u32>>,
 
     /// tensor dimensions out (optional)
     #[serde(default)]
     pub tensor_shape_out: Option<Vec<u32>>,
+
+    /// batch axis of the input tensors (optional)
+    /// Models which declare it are compiled once for any batch size,
+    /// e.g. `0` for inputs of shape `N x 3 x 224 x 224`.
+    #[serde(default)]
+    pub batch_axis: Option<usize>,
 }
 
 impl ModelMetadata {
@@ -63,8 +69,8 @@ pub async fn untar_model_and_metadata(data: Vec<u8>) -> DataLoaderResult<(Vec<u8
                 })
             })
         })
-        .ok_or_else(|| format!("No JSON file found in the tar archive"))
-        .map_err(|e| DataLoaderError::ModelLoaderTarError(format!("{}", e)))?
+        .ok_or("No JSON file found in the tar archive")
+        .map_err(|e| DataLoaderError::ModelLoaderTarError(e.to_string()))?
         .map_err(|e| DataLoaderError::ModelLoaderTarError(format!("{}", e)))?;
 
     let mut metadata: Vec<u8> = Vec::new();
@@ -81,8 +87,8 @@ pub async fn untar_model_and_metadata(data: Vec<u8>) -> DataLoaderResult<(Vec<u8
                 })
             })
         })
-        .ok_or_else(|| format!("No model found in the tar archive"))
-        .map_err(|e| DataLoaderError::ModelLoaderTarError(format!("{}", e)))?
+        .ok_or("No model found in the tar archive")
+        .map_err(|e| DataLoaderError::ModelLoaderTarError(e.to_string()))?
         .map_err(|e| DataLoaderError::ModelLoaderTarError(format!("{}", e)))?;
 
     let mut model: Vec<u8> = Vec::new();
@@ -7,7 +7,7 @@ use oci_distribution::{
     client::{ClientConfig, ImageData},
 };
 
-pub async fn pull_image(image_ref: &str, content_type: &str) -> DataLoaderResult<ImageData> {
+fn client() -> Client {
     // Experimenting with an ordinary local docker registry,
     // the protocol is `Http`
     let config = ClientConfig {
@@ -15,7 +15,11 @@ pub async fn pull_image(image_ref: &str, content_type: &str) -> DataLoaderResult
         ..Default::default()
     };
 
-    let client = Client::new(config);
+    Client::new(config)
+}
+
+pub async fn pull_image(image_ref: &str, content_type: &str) -> DataLoaderResult<ImageData> {
+    let client = client();
 
     let reference: Reference = image_ref.parse().unwrap();
 
@@ -29,6 +33,18 @@ pub async fn pull_image(image_ref: &str, content_type: &str) -> DataLoaderResult
     Ok(image_data)
 }
 
+/// Returns the digest of the image's manifest, without pulling the image.
+pub async fn fetch_manifest_digest(image_ref: &str) -> DataLoaderResult<String> {
+    let reference: Reference = image_ref
+        .parse()
+        .map_err(|error| DataLoaderError::OciImageLoadError(format!("{}", error)))?;
+
+    client()
+        .fetch_manifest_digest(&reference, &RegistryAuth::Anonymous)
+        .await
+        .map_err(|error| DataLoaderError::OciImageLoadError(format!("{}", error)))
+}
+
 pub async fn uncompress_layer(data: Vec<u8>) -> DataLoaderResult<Vec<u8>> {
     let mut decompressed_data = Vec::new();
     let mut gz_decoder = GzDecoder::new(&data[..]);
@@ -42,7 +58,8 @@ pub async fn uncompress_layer(data: Vec<u8>) -> DataLoaderResult<Vec<u8>> {
     Ok(decompressed_data)
 }
 
-pub async fn read_first_layer(image_data: ImageData) -> DataLoaderResult<Vec<u8>> {
+/// Returns the sha256 digest and the data of the image's first layer.
+pub async fn read_first_layer(image_data: ImageData) -> DataLoaderResult<(String, Vec<u8>)> {
     println!("This image has {} layer(s)", &image_data.layers.len());
 
     // The example image is supposed to have one layer.
@@ -51,10 +68,10 @@ pub async fn read_first_layer(image_data: ImageData) -> DataLoaderResult<Vec<u8>
     let first_layer = image_data
         .layers
         .into_iter()
-        .nth(0)
+        .next()
         .ok_or(DataLoaderError::OciLayerLoadError)?;
 
     log::info!("First layer succesfully read!");
 
-    Ok(first_layer.data)
-}
+    Ok((first_layer.sha256_digest(), first_layer.data))
+}
\ No newline at end of file
@@ -12,7 +12,9 @@ use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 
 #[cfg(any(feature = "tflite", feature = "edgetpu"))]
@@ -21,7 +23,8 @@ use crate::engine::tflite::TfLiteEngine;
 // pub use tract::{bytes_to_f32_vec, f32_array_to_bytes, TractEngine, TractSession};
 
 // use wasmcloud_interface_mlinference::{InferenceOutput, Tensor};
-use crate::{DataType, Tensor};
+use crate::wamli::ml::types;
+use crate::{DataType, MlError, Tensor, TensorInfo};
 
 /// Graph (model number)
 pub type Graph = u32;
@@ -32,7 +35,7 @@ pub type Engine = Arc<Box<dyn InferenceEngine + Send + Sync>>;
 pub type GraphExecutionContext = u32;
 
 pub type ModelId = String;
-pub type ModelZoo = HashMap<ModelId, ModelContext>;
+pub type ModelZoo = HashMap<ModelId, ModelEntry>;
 
 impl FromStr for DataType {
     type Err = DataLoaderError;
@@ -67,6 +70,18 @@ impl FromStr for DataType {
     }
 }
 
+/// Size of a single element of given data type in bytes, `None` for `NA`
+pub fn element_size(dtype: &DataType) -> Option<usize> {
+    match dtype {
+        DataType::U8 | DataType::S8 => Some(1),
+        DataType::U16 | DataType::S16 | DataType::F16 => Some(2),
+        DataType::U32 | DataType::S32 | DataType::F32 => Some(4),
+        DataType::U64 | DataType::S64 | DataType::F64 => Some(8),
+        DataType::U128 | DataType::S128 | DataType::F128 => Some(16),
+        DataType::Na => None,
+    }
+}
+
 /// GraphEncoding
 #[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -76,6 +91,19 @@ pub enum GraphEncoding {
     TfLite,
     OpenVino,
     Tensorflow,
+    Nnef,
+}
+
+impl From<GraphEncoding> for types::GraphEncoding {
+    fn from(encoding: GraphEncoding) -> Self {
+        match encoding {
+            GraphEncoding::Onnx => types::GraphEncoding::Onnx,
+            GraphEncoding::TfLite => types::GraphEncoding::Tflite,
+            GraphEncoding::OpenVino => types::GraphEncoding::Openvino,
+            GraphEncoding::Tensorflow => types::GraphEncoding::Tensorflow,
+            GraphEncoding::Nnef => types::GraphEncoding::Nnef,
+        }
+    }
 }
 
 impl FromStr for GraphEncoding {
@@ -87,6 +115,7 @@ impl FromStr for GraphEncoding {
             "tflite" => Ok(GraphEncoding::TfLite),
             "openvino" => Ok(GraphEncoding::OpenVino),
             "tensorflow" => Ok(GraphEncoding::Tensorflow),
+            "nnef" => Ok(GraphEncoding::Nnef),
             _ => Err(DataLoaderError::ModelLoaderMetadataError(format!(
                 "Invalid graph encoding: '{}'",
                 s
@@ -113,6 +142,17 @@ pub enum ExecutionTarget {
     Tpu,
 }
 
+impl From<&ExecutionTarget> for types::ExecutionTarget {
+    fn from(target: &ExecutionTarget) -> Self {
+        match target {
+            ExecutionTarget::Cpu => types::ExecutionTarget::Cpu,
+            ExecutionTarget::Gpu => types::ExecutionTarget::Gpu,
+            ExecutionTarget::Npu => types::ExecutionTarget::Npu,
+            ExecutionTarget::Tpu => types::ExecutionTarget::Tpu,
+        }
+    }
+}
+
 impl FromStr for ExecutionTarget {
     type Err = DataLoaderError;
 
@@ -135,23 +175,42 @@ impl FromStr for ExecutionTarget {
 }
 
 // #[derive(Clone, Debug, PartialEq, Deserialize)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct ModelContext {
     pub model_name: String,
+    /// identifies the model's content, see `LoadedGraph`
+    pub digest: String,
     pub graph_encoding: GraphEncoding,
     pub execution_target: ExecutionTarget,
     pub dtype: DataType,
+    /// input shape as declared by the model's metadata
+    pub tensor_shape_in: Option<Vec<u32>>,
+    /// output shape as declared by the model's metadata
+    pub tensor_shape_out: Option<Vec<u32>>,
+    /// axis of the inputs which holds the batch, as declared by the model's metadata
+    pub batch_axis: Option<usize>,
+    /// inputs as declared by the graph, complemented by the metadata,
+    /// which incoming tensors are validated against
+    pub inputs: Vec<TensorInfo>,
+    /// duration of the warm-up inference at registration, if there was any
+    pub warmup_latency: Option<Duration>,
     pub graph_execution_context: GraphExecutionContext,
     pub graph: Graph,
 }
 
-impl ModelContext {
-    pub fn default() -> ModelContext {
+impl Default for ModelContext {
+    fn default() -> Self {
         ModelContext {
             model_name: Default::default(),
+            digest: Default::default(),
             graph_encoding: Default::default(),
             execution_target: Default::default(),
             dtype: DataType::F32,
+            tensor_shape_in: None,
+            tensor_shape_out: None,
+            batch_axis: None,
+            inputs: Vec::new(),
+            warmup_latency: None,
             graph_execution_context: Default::default(),
             graph: Default::default(),
         }
@@ -170,6 +229,175 @@ impl ModelContext {
     // }
 }
 
+/// A graph as loaded into its engine. Model ids which refer to the very same
+/// content, i.e. the same digest, share the graph. The engine's state is only
+/// dropped once the last of them is unloaded, see `GraphHandle`.
+#[derive(Clone, Debug)]
+pub struct LoadedGraph {
+    pub handle: Arc<GraphHandle>,
+    /// size of the raw model in bytes
+    pub size: usize,
+    /// number of `ModelContext`s referring to the graph
+    pub references: usize,
+}
+
+/// Keeps the engine's state of a graph alive. Inferences hold a handle while they
+/// are queued or running, such that the state is only dropped along with the last
+/// handle, i.e. once the graph is released and its inferences have drained.
+pub struct GraphHandle {
+    engine: Engine,
+    pub graph: Graph,
+    pub graph_execution_context: GraphExecutionContext,
+    /// runtime the engine's state is dropped on, since the last handle
+    /// may as well be dropped on an inference worker
+    runtime: tokio::runtime::Handle,
+}
+
+impl GraphHandle {
+    /// Must be called within the runtime, see `tokio::runtime::Handle::current()`.
+    pub fn new(engine: Engine, graph: Graph, graph_execution_context: GraphExecutionContext) -> Self {
+        Self {
+            engine,
+            graph,
+            graph_execution_context,
+            runtime: tokio::runtime::Handle::current(),
+        }
+    }
+}
+
+impl Drop for GraphHandle {
+    fn drop(&mut self) {
+        let engine = Arc::clone(&self.engine);
+        let graph = self.graph;
+        let gec = self.graph_execution_context;
+
+        self.runtime.spawn(async move {
+            engine.drop_model_state(&graph, &gec).await;
+            log::debug!("dropped engine state of graph {} with context {}", graph, gec);
+        });
+    }
+}
+
+impl std::fmt::Debug for GraphHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GraphHandle")
+            .field("graph", &self.graph)
+            .field("graph_execution_context", &self.graph_execution_context)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Load state of a model in the `ModelZoo`
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadState {
+    Pending,
+    Downloading,
+    Compiling,
+    Ready,
+    Failed(String),
+}
+
+impl LoadState {
+    /// Whether the model is on its way to become ready
+    pub fn is_loading(&self) -> bool {
+        matches!(
+            self,
+            LoadState::Pending | LoadState::Downloading | LoadState::Compiling
+        )
+    }
+}
+
+impl std::fmt::Display for LoadState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadState::Pending => write!(f, "pending"),
+            LoadState::Downloading => write!(f, "downloading"),
+            LoadState::Compiling => write!(f, "compiling"),
+            LoadState::Ready => write!(f, "ready"),
+            LoadState::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+impl From<&LoadState> for types::LoadState {
+    fn from(state: &LoadState) -> Self {
+        match state {
+            LoadState::Pending => types::LoadState::Pending,
+            LoadState::Downloading => types::LoadState::Downloading,
+            LoadState::Compiling => types::LoadState::Compiling,
+            LoadState::Ready => types::LoadState::Ready,
+            LoadState::Failed(reason) => types::LoadState::Failed(reason.clone()),
+        }
+    }
+}
+
+/// Entry of the `ModelZoo`, which tracks a model from the beginning of its load.
+#[derive(Debug)]
+pub struct ModelEntry {
+    pub state: LoadState,
+    /// Only available once the model is ready
+    pub context: Option<ModelContext>,
+    pub loaded_at: Option<SystemTime>,
+    /// Most recent use in milliseconds since the unix epoch, 0 if never used.
+    /// Atomic, such that predictions only require read access to the `ModelZoo`.
+    last_used: AtomicU64,
+}
+
+impl ModelEntry {
+    pub fn pending() -> Self {
+        Self {
+            state: LoadState::Pending,
+            context: None,
+            loaded_at: None,
+            last_used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn ready(context: ModelContext) -> Self {
+        Self {
+            state: LoadState::Ready,
+            context: Some(context),
+            loaded_at: Some(SystemTime::now()),
+            last_used: AtomicU64::new(0),
+        }
+    }
+
+    /// The model's context, in case the model is ready
+    pub fn ready_context(&self) -> Option<&ModelContext> {
+        match self.state {
+            LoadState::Ready => self.context.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Records that the model has just been used.
+    pub fn touch(&self) {
+        self.last_used.store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    pub fn last_used(&self) -> Option<u64> {
+        match self.last_used.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(millis),
+        }
+    }
+
+    /// Most recent use, or the time the model became ready if it has not
+    /// been used yet, in milliseconds since the unix epoch
+    pub fn last_active(&self) -> u64 {
+        self.last_used()
+            .or_else(|| self.loaded_at.map(unix_millis))
+            .unwrap_or_default()
+    }
+}
+
+/// Milliseconds since the unix epoch
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 /// InferenceEngine
 #[async_trait]
 pub trait InferenceEngine {
@@ -197,9 +425,97 @@ pub trait InferenceEngine {
         index: u32,
     ) -> InferenceResult<Tensor>;
 
+    /// Runs a complete inference as a single request: assigns the given
+    /// tensors to the inputs of given index, computes and returns all outputs.
+    /// Fails with `CorruptInputTensor` in case an input is assigned more than once.
+    /// Unlike `set_input`, `compute` and `get_output`, concurrent requests
+    /// do not interfere with each other.
+    async fn infer(
+        &self,
+        context: GraphExecutionContext,
+        inputs: Vec<(u32, Tensor)>,
+    ) -> InferenceResult<Vec<Tensor>>;
+
+    /// Names of the model's inputs, ordered by their index.
+    async fn input_names(&self, context: GraphExecutionContext) -> InferenceResult<Vec<String>>;
+
+    /// Names of the model's outputs, ordered by their index.
+    async fn output_names(&self, context: GraphExecutionContext) -> InferenceResult<Vec<String>>;
+
+    /// Names, shapes and data types of the model's inputs, ordered by their index.
+    /// Anything the engine cannot determine from the graph itself is left open.
+    async fn input_infos(
+        &self,
+        context: GraphExecutionContext,
+    ) -> InferenceResult<Vec<TensorInfo>>;
+
+    /// Names, shapes and data types of the model's outputs, ordered by their index.
+    async fn output_infos(
+        &self,
+        context: GraphExecutionContext,
+    ) -> InferenceResult<Vec<TensorInfo>>;
+
+    /// Like `set_input`, but addresses the input by its name.
+    async fn set_input_by_name(
+        &self,
+        context: GraphExecutionContext,
+        name: &str,
+        tensor: &Tensor,
+    ) -> InferenceResult<()> {
+        let index = index_of(name, &self.input_names(context).await?)?;
+        self.set_input(context, index, tensor).await
+    }
+
+    /// Like `get_output`, but addresses the output by its name.
+    async fn get_output_by_name(
+        &self,
+        context: GraphExecutionContext,
+        name: &str,
+    ) -> InferenceResult<Tensor> {
+        let index = index_of(name, &self.output_names(context).await?)?;
+        self.get_output(context, index).await
+    }
+
+    /// Declares the axis of the inputs which holds the batch, such that the model
+    /// is compiled once for any batch size. Fails with `FixedBatchSize` in case the
+    /// model only runs with the batch size it is compiled for, which is the case
+    /// for all models of engines which only run models of fixed shape.
+    async fn set_batch_axis(
+        &self,
+        _context: GraphExecutionContext,
+        axis: usize,
+    ) -> InferenceResult<()> {
+        Err(InferenceError::FixedBatchSize(axis))
+    }
+
+    /// Serializes the model as an NNEF archive, which loads as `GraphEncoding::Nnef`,
+    /// compiled for an input of given data type and shape, if any. `None` in case
+    /// the engine cannot serialize its models, see `CompileCache`.
+    async fn compile_to_nnef(
+        &self,
+        _context: GraphExecutionContext,
+        _input: Option<(DataType, Vec<u32>)>,
+    ) -> InferenceResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
     async fn drop_model_state(&self, graph: &Graph, gec: &GraphExecutionContext);
 }
 
+/// Resolve a tensor name into its index within `names`.
+pub fn index_of(name: &str, names: &[String]) -> InferenceResult<u32> {
+    match names.iter().position(|n| n == name) {
+        Some(index) => Ok(index as u32),
+        None => {
+            log::error!("index_of() - unknown tensor name '{}', valid names are {:?}", name, names);
+            Err(InferenceError::UnknownTensorName {
+                name: name.to_owned(),
+                valid_names: names.to_vec(),
+            })
+        }
+    }
+}
+
 // impl Default for Box<dyn InferenceEngine + Send + Sync> {
 //     fn default() -> Box<dyn InferenceEngine + Send + Sync>
 //     where
@@ -218,7 +534,7 @@ pub async fn get_engine(
     log::debug!("get_engine() - context: {:?}", &encoding);
 
     match encoding {
-        GraphEncoding::Onnx | GraphEncoding::Tensorflow => {
+        encoding if runs_on_tract(encoding) => {
             match engines_lock.get(&InferenceFramework::Tract) {
                 Some(e) => Ok(e.clone()),
                 None => Err(InferenceError::InvalidEncodingError),
@@ -241,6 +557,16 @@ pub async fn get_engine(
     }
 }
 
+/// Whether models of given encoding are run by the tract engine. TfLite
+/// models are, unless the TensorFlow Lite C++ backend is compiled in.
+fn runs_on_tract(encoding: &GraphEncoding) -> bool {
+    match encoding {
+        GraphEncoding::Onnx | GraphEncoding::Tensorflow | GraphEncoding::Nnef => true,
+        GraphEncoding::TfLite => !cfg!(any(feature = "tflite", feature = "edgetpu")),
+        _ => false,
+    }
+}
+
 /// Each link definition may address a different target
 /// such that it may be necessary to support multiple engines.
 pub async fn get_or_else_set_engine(
@@ -250,10 +576,10 @@ pub async fn get_or_else_set_engine(
     let mut engines_lock = engines.write().await;
 
     match encoding {
-        GraphEncoding::Onnx | GraphEncoding::Tensorflow => {
+        encoding if runs_on_tract(encoding) => {
             match engines_lock.get(&InferenceFramework::Tract) {
                 Some(e) => {
-                    log::debug!("get_or_else_set_engine() - previously created Onnx/Tensorflow engine selected for '{:?}'.", &encoding);
+                    log::debug!("get_or_else_set_engine() - previously created Tract engine selected for '{:?}'.", &encoding);
                     Ok(e.clone())
                 }
                 None => {
@@ -261,14 +587,14 @@ pub async fn get_or_else_set_engine(
                         InferenceFramework::Tract,
                         Arc::new(Box::new(TractEngine::default())),
                     );
-                    log::debug!("get_or_else_set_engine() - Onnx/Tensorflow engine selected and created for '{:?}'.", &encoding);
+                    log::debug!("get_or_else_set_engine() - Tract engine selected and created for '{:?}'.", &encoding);
 
                     if feedback.is_none() {
                         log::debug!(
-                            "get_or_else_set_engine() - Onnx/Tensorflow was definitely created"
+                            "get_or_else_set_engine() - Tract engine was definitely created"
                         );
                     } else {
-                        log::debug!("get_or_else_set_engine() - Onnx/Tensorflow was NOT created!");
+                        log::debug!("get_or_else_set_engine() - Tract engine was NOT created!");
                     }
 
                     log::debug!(
@@ -290,7 +616,7 @@ pub async fn get_or_else_set_engine(
         #[cfg(any(feature = "tflite", feature = "edgetpu"))]
         GraphEncoding::TfLite => match engines_lock.get(&InferenceFramework::TfLite) {
             Some(e) => {
-                log::debug!("get_or_else_set_engine() - previously created TfLite engine selected for '{}'.", encoding.model_name);
+                log::debug!("get_or_else_set_engine() - previously created TfLite engine selected for '{:?}'.", &encoding);
                 Ok(e.clone())
             }
             None => {
@@ -299,8 +625,8 @@ pub async fn get_or_else_set_engine(
                     Arc::new(Box::new(TfLiteEngine::default())),
                 );
                 log::debug!(
-                    "get_or_else_set_engine() - TfLite engine selected and created for '{}'.",
-                    encoding.model_name
+                    "get_or_else_set_engine() - TfLite engine selected and created for '{:?}'.",
+                    &encoding
                 );
 
                 if feedback.is_none() {
@@ -336,6 +662,9 @@ pub enum InferenceError {
     #[error("runtime error")]
     RuntimeError,
 
+    #[error("Graph execution context {0} not found, its model has been unloaded")]
+    ContextNotFound(GraphExecutionContext),
+
     #[error("ONNX error")]
     OnnxError,
 
@@ -366,6 +695,18 @@ pub enum InferenceError {
     #[error("Corrupt input tensor")]
     CorruptInputTensor,
 
+    #[error("Unsupported data type {0}")]
+    UnsupportedDataType(String),
+
+    #[error("Unknown tensor name '{name}', valid names are {valid_names:?}")]
+    UnknownTensorName {
+        name: String,
+        valid_names: Vec<String>,
+    },
+
+    #[error("Model is compiled for a fixed size along batch axis {0}")]
+    FixedBatchSize(usize),
+
     #[error("Re-shaping of tensor failed {0}")]
     ReShapeError(String),
 
@@ -373,5 +714,37 @@ pub enum InferenceError {
     BytesToVecConversionError(#[from] std::io::Error),
 
     #[error("Configuration of model's input type and/or shape failed")]
-    CorruptInputTypeOrShape(#[from] tract_onnx::tract_core::anyhow::Error),
+    CorruptInputTypeOrShape(#[from] tract_onnx::prelude::TractError),
+}
+
+impl From<InferenceError> for MlError {
+    fn from(error: InferenceError) -> Self {
+        match error {
+            InferenceError::OnnxError => MlError::OnnxError(error.to_string()),
+            InferenceError::UnsupportedExecutionTarget | InferenceError::InvalidEncodingError => {
+                MlError::InvalidEncoding(error.to_string())
+            }
+            InferenceError::FailedToBuildModelFromBuffer | InferenceError::FixedBatchSize(_) => {
+                MlError::InvalidModel(error.to_string())
+            }
+            InferenceError::EdgeTPUAllocationError
+            | InferenceError::InterpreterBuilderError
+            | InferenceError::InterpreterBuildError
+            | InferenceError::InterpreterInvocationError
+            | InferenceError::TensorAllocationError => MlError::TfliteError(error.to_string()),
+            InferenceError::CorruptInputTensor
+            | InferenceError::UnsupportedDataType(_)
+            | InferenceError::ReShapeError(_)
+            | InferenceError::BytesToVecConversionError(_) => {
+                MlError::CorruptInputTensor(error.to_string())
+            }
+            InferenceError::UnknownTensorName { .. } => {
+                MlError::UnknownTensorName(error.to_string())
+            }
+            InferenceError::ContextNotFound(_) => MlError::ContextNotFoundError(error.to_string()),
+            InferenceError::RuntimeError | InferenceError::CorruptInputTypeOrShape(_) => {
+                MlError::RuntimeError(format!("{:#}", anyhow::Error::from(error)))
+            }
+        }
+    }
 }
@@ -1,50 +1,41 @@
-use crate::inference::{
+use crate::engine::{
     DataType, ExecutionTarget, Graph, GraphEncoding, GraphExecutionContext, InferenceEngine,
     InferenceError, InferenceResult, Tensor,
 };
+use crate::{DimensionInfo, TensorInfo};
 use async_trait::async_trait;
 #[cfg(feature = "edgetpu")]
 use edgetpu::EdgeTpuContext;
-use std::collections::{btree_map::Keys, BTreeMap};
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 #[cfg(any(feature = "tflite", feature = "edgetpu"))]
 use tflite::{
-    op_resolver::OpResolver, ops::builtin::BuiltinOpResolver, FlatBufferModel, Interpreter,
-    InterpreterBuilder,
-};
-use tokio::sync::RwLock;
-use wasmcloud_interface_mlinference::{
-    InferenceOutput, Status, Tensor, ValueType, TENSOR_FLAG_ROW_MAJOR,
+    context::ElementKind, op_resolver::OpResolver, ops::builtin::BuiltinOpResolver,
+    FlatBufferModel, Interpreter, InterpreterBuilder, TensorIndex,
 };
+use tokio::sync::{Mutex, RwLock};
 
 #[derive(Default, Clone)]
 pub struct TfLiteEngine<'a> {
     state: Arc<RwLock<ModelState<'a>>>,
 }
 
+/// The engine's state is only locked for looking up or (de-)registering
+/// sessions. Each session owns its lock, since an interpreter must not
+/// be invoked concurrently.
 #[derive(Default)]
 pub struct ModelState<'a> {
-    executions: BTreeMap<GraphExecutionContext, TfLiteSession<'a, BuiltinOpResolver>>,
+    executions: BTreeMap<GraphExecutionContext, Arc<Mutex<TfLiteSession<'a, BuiltinOpResolver>>>>,
     models: BTreeMap<Graph, Vec<u8>>,
-}
-
-impl<'a> ModelState<'a> {
-    /// Helper function that returns the key that is supposed to be inserted next.
-    pub fn key<K: Into<u32> + From<u32> + Copy, V>(&self, keys: Keys<K, V>) -> K {
-        match keys.last() {
-            Some(&k) => {
-                let last: u32 = k.into();
-                K::from(last + 1)
-            }
-            None => K::from(0),
-        }
-    }
+    /// number of models loaded so far, the keys of raw models are never reused
+    models_loaded: Graph,
+    /// number of execution contexts initialized so far, whose keys are not reused either
+    contexts_initialized: GraphExecutionContext,
 }
 
 pub struct TfLiteSession<'a, BuiltinOpResolver: OpResolver> {
     pub graph: Interpreter<'a, BuiltinOpResolver>,
     pub encoding: GraphEncoding,
-    pub input_tensors: usize,
     pub output_tensors: Option<Vec<Tensor>>,
     #[cfg(feature = "edgetpu")]
     pub edgetpu_context: Option<edgetpu::EdgeTpuContext>,
@@ -61,10 +52,127 @@ impl<'a> TfLiteSession<'a, BuiltinOpResolver> {
             encoding,
             #[cfg(feature = "edgetpu")]
             edgetpu_context,
-            input_tensors: 0,
             output_tensors: None,
         }
     }
+
+    /// Copies the tensor's data into the interpreter's input of given index.
+    pub fn set_input(&mut self, index: u32, tensor: &Tensor) -> InferenceResult<()> {
+        let tensor_index = match self.graph.inputs().get(index as usize) {
+            Some(&ti) => ti,
+            None => {
+                log::error!("set_input() - model does not have an input with index {}", index);
+                return Err(InferenceError::RuntimeError);
+            }
+        };
+
+        let tensor_info = self.graph.tensor_info(tensor_index).ok_or_else(|| {
+            log::error!("set_input() - info for input tensor could not be evaluated");
+            InferenceError::RuntimeError
+        })?;
+
+        log::debug!("set_input() - required shape: {:?}", tensor_info.dims);
+
+        match tensor_info.element_kind {
+            ElementKind::kTfLiteUInt8 => {
+                let input: &mut [u8] = self.graph.tensor_data_mut(tensor_index).map_err(|_| {
+                    log::error!("set_input() - failed to get input tensor");
+                    InferenceError::RuntimeError
+                })?;
+                check_length(&tensor_info.name, input.len(), tensor.data.len())?;
+                input.copy_from_slice(&tensor.data);
+            }
+            ElementKind::kTfLiteFloat32 => {
+                let input: &mut [f32] = self.graph.tensor_data_mut(tensor_index).map_err(|_| {
+                    log::error!("set_input() - failed to get input tensor");
+                    InferenceError::RuntimeError
+                })?;
+                check_length(&tensor_info.name, input.len() * 4, tensor.data.len())?;
+                for (value, chunk) in input.iter_mut().zip(tensor.data.chunks_exact(4)) {
+                    *value = f32::from_le_bytes(chunk.try_into().unwrap());
+                }
+            }
+            kind => {
+                log::error!(
+                    "set_input() - input tensor '{}' has unsupported type {:?}",
+                    tensor_info.name,
+                    kind
+                );
+                return Err(InferenceError::UnsupportedDataType(format!("{:?}", kind)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Invokes the interpreter and collects its outputs.
+    pub fn compute(&mut self) -> InferenceResult<()> {
+        let interpreter = &mut self.graph;
+
+        interpreter.invoke().map_err(|_| {
+            log::error!("compute() - interpreter invocation failed");
+            InferenceError::InterpreterInvocationError
+        })?;
+
+        let output_tensors = interpreter.outputs();
+
+        log::debug!(
+            "compute() - output tensors contains {} elements",
+            output_tensors.len()
+        );
+
+        let mut result_tensors: Vec<Tensor> = Vec::new();
+
+        for &output in output_tensors {
+            let tensor_info = interpreter.tensor_info(output).ok_or_else(|| {
+                log::error!("compute() - info for output tensor could not be evaluated");
+                return InferenceError::RuntimeError;
+            })?;
+
+            // quantized outputs are dequantized, such that all outputs are f32
+            let results: Vec<f32> = match tensor_info.element_kind {
+                ElementKind::kTfLiteUInt8 => {
+                    let out_tensor: &[u8] = interpreter.tensor_data(output).map_err(|_| {
+                        log::error!("compute() - failed to get output tensor");
+                        InferenceError::FailedToBuildModelFromBuffer
+                    })?;
+                    let scale = tensor_info.params.scale;
+                    let zero_point = tensor_info.params.zero_point;
+                    out_tensor
+                        .iter()
+                        .map(|&x| scale * (((x as i32) - zero_point) as f32))
+                        .collect()
+                }
+                ElementKind::kTfLiteFloat32 => {
+                    let out_tensor: &[f32] = interpreter.tensor_data(output).map_err(|_| {
+                        log::error!("compute() - failed to get output tensor");
+                        InferenceError::FailedToBuildModelFromBuffer
+                    })?;
+                    out_tensor.to_vec()
+                }
+                kind => {
+                    log::error!(
+                        "compute() - output tensor '{}' has unsupported type {:?}",
+                        tensor_info.name,
+                        kind
+                    );
+                    return Err(InferenceError::UnsupportedDataType(format!("{:?}", kind)));
+                }
+            };
+
+            let result_tensor = Tensor {
+                shape: tensor_info.dims.into_iter().map(|i| i as u32).collect(),
+                dtype: DataType::F32,
+                data: f32_vec_to_bytes(results).into(),
+            };
+
+            result_tensors.push(result_tensor);
+        }
+
+        self.output_tensors.replace(result_tensors);
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -74,7 +182,8 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
         let model_bytes = model.to_vec();
 
         let mut state = self.state.write().await;
-        let graph = state.key(state.models.keys());
+        let graph = state.models_loaded;
+        state.models_loaded += 1;
 
         log::debug!(
             "load() - inserting graph: {:#?} with size {:#?}",
@@ -191,7 +300,8 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
             InferenceError::TensorAllocationError
         })?;
 
-        let gec = state.key(state.executions.keys());
+        let gec = state.contexts_initialized;
+        state.contexts_initialized += 1;
 
         log::debug!(
             "init_execution_context() - inserting graph execution context: {:#?}",
@@ -200,12 +310,12 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
 
         state.executions.insert(
             gec,
-            TfLiteSession::with_graph(
+            Arc::new(Mutex::new(TfLiteSession::with_graph(
                 interpreter,
                 encoding.to_owned(),
                 #[cfg(feature = "edgetpu")]
                 edgetpu_context,
-            ),
+            ))),
         );
 
         log::debug!("init_execution_context() - passed");
@@ -227,33 +337,18 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
             tensor
         );
 
-        let mut state = self.state.write().await;
-        let execution = match state.executions.get_mut(&context) {
-            Some(s) => s,
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
             None => {
                 log::error!(
                     "set_input() - cannot find session in state with context {:#?}",
                     context
                 );
-                return Err(InferenceError::RuntimeError);
+                return Err(InferenceError::ContextNotFound(context));
             }
         };
 
-        let tensor_index = execution.graph.inputs()[execution.input_tensors];
-
-        // prepare for multiple input tensors
-        execution.input_tensors = execution.input_tensors + 1;
-
-        log::debug!(
-            "set_input() - required shape: {:?}",
-            execution.graph.tensor_info(tensor_index).unwrap().dims,
-        );
-
-        execution
-            .graph
-            .tensor_data_mut(tensor_index)
-            .unwrap()
-            .copy_from_slice(tensor.data.as_slice());
+        session.lock().await.set_input(index, tensor)?;
 
         log::debug!("set_input() - passed");
 
@@ -264,83 +359,137 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
     async fn compute(&self, context: GraphExecutionContext) -> InferenceResult<()> {
         log::debug!("compute() - entering");
 
-        let mut state = self.state.write().await;
-        let execution = match state.executions.get_mut(&context) {
-            Some(s) => s,
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
             None => {
                 log::error!(
                     "compute() - cannot find session in state with context {:#?}",
                     context
                 );
-                return Err(InferenceError::RuntimeError);
+                return Err(InferenceError::ContextNotFound(context));
             }
         };
 
-        let interpreter = &mut execution.graph;
+        session.lock().await.compute()
+    }
 
-        interpreter.invoke().map_err(|_| {
-            log::error!("init_execution_context() - interpreter invokation failed");
-            InferenceError::InterpreterInvocationError
-        })?;
+    /// infer
+    async fn infer(
+        &self,
+        context: GraphExecutionContext,
+        inputs: Vec<(u32, Tensor)>,
+    ) -> InferenceResult<Vec<Tensor>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "infer() - cannot find session in state with context {:#?}",
+                    context
+                );
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
 
-        let output_tensors = interpreter.outputs();
+        // the session stays locked for the whole request, such that
+        // concurrent requests cannot overwrite each other's inputs
+        let mut execution = session.lock().await;
 
-        log::debug!(
-            "compute() - output tensors contains {} elements",
-            output_tensors.len()
-        );
+        let mut assigned = BTreeSet::new();
+        for (index, tensor) in inputs.iter() {
+            if !assigned.insert(*index) {
+                log::error!("infer() - input {} is assigned more than once", index);
+                return Err(InferenceError::CorruptInputTensor);
+            }
+            execution.set_input(*index, tensor)?;
+        }
 
-        let mut result_tensors: Vec<Tensor> = Vec::new();
+        execution.compute()?;
 
-        for &output in output_tensors {
-            let mut results = Vec::new();
-            let tensor_info = interpreter.tensor_info(output).ok_or_else(|| {
-                log::error!("compute() - info for output tensor could not be evaluated");
-                return InferenceError::RuntimeError;
-            })?;
+        Ok(execution.output_tensors.take().unwrap_or_default())
+    }
 
-            match tensor_info.element_kind {
-                tflite::context::ElementKind::kTfLiteUInt8 => {
-                    let out_tensor: &[u8] = interpreter.tensor_data(output).map_err(|_| {
-                        log::error!("compute() - failed to get output tensor");
-                        InferenceError::FailedToBuildModelFromBuffer
-                    })?;
-                    let scale = tensor_info.params.scale;
-                    let zero_point = tensor_info.params.zero_point;
-                    results = out_tensor
-                        .into_iter()
-                        .map(|&x| scale * (((x as i32) - zero_point) as f32))
-                        .collect();
-                }
-                tflite::context::ElementKind::kTfLiteFloat32 => {
-                    let out_tensor: &[f32] = interpreter.tensor_data(output).map_err(|_| {
-                        log::error!("compute() - failed to get output tensor");
-                        InferenceError::FailedToBuildModelFromBuffer
-                    })?;
-                    results = out_tensor.into_iter().copied().collect();
-                }
-                _ => eprintln!(
-                    "Tensor {} has unsupported output type {:?}.",
-                    tensor_info.name, tensor_info.element_kind,
-                ),
+    /// input_names
+    async fn input_names(&self, context: GraphExecutionContext) -> InferenceResult<Vec<String>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "input_names() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
             }
+        };
+        let execution = session.lock().await;
 
-            let bytes = f32_vec_to_bytes(results).await;
+        tensor_names(&execution.graph, execution.graph.inputs())
+    }
 
-            let result_tensor = Tensor {
-                value_types: vec![ValueType::ValueF32],
-                dimensions: tensor_info.dims.into_iter().map(|i| i as u32).collect(),
-                flags: TENSOR_FLAG_ROW_MAJOR,
-                data: bytes,
-            };
+    /// output_names
+    async fn output_names(&self, context: GraphExecutionContext) -> InferenceResult<Vec<String>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "output_names() - cannot find session in state with context {:#?}",
+                    context
+                );
 
-            result_tensors.push(result_tensor);
-        }
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
 
-        execution.input_tensors = 0;
-        execution.output_tensors.replace(result_tensors);
+        tensor_names(&execution.graph, execution.graph.outputs())
+    }
 
-        Ok(())
+    /// input_infos
+    async fn input_infos(
+        &self,
+        context: GraphExecutionContext,
+    ) -> InferenceResult<Vec<TensorInfo>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "input_infos() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
+
+        tensor_infos(&execution.graph, execution.graph.inputs())
+    }
+
+    /// output_infos
+    async fn output_infos(
+        &self,
+        context: GraphExecutionContext,
+    ) -> InferenceResult<Vec<TensorInfo>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "output_infos() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
+
+        // outputs are always dequantized to f32, see `TfLiteSession::compute()`
+        let mut infos = tensor_infos(&execution.graph, execution.graph.outputs())?;
+        for info in infos.iter_mut() {
+            info.dtype = Some(DataType::F32);
+        }
+
+        Ok(infos)
     }
 
     /// get_output
@@ -348,19 +497,19 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
         &self,
         context: GraphExecutionContext,
         index: u32,
-    ) -> InferenceResult<InferenceOutput> {
-        let state = self.state.read().await;
-        let execution = match state.executions.get(&context) {
-            Some(s) => s,
+    ) -> InferenceResult<Tensor> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
             None => {
                 log::error!(
-                    "compute() - cannot find session in state with context {:#?}",
+                    "get_output() - cannot find session in state with context {:#?}",
                     context
                 );
 
-                return Err(InferenceError::RuntimeError);
+                return Err(InferenceError::ContextNotFound(context));
             }
         };
+        let execution = session.lock().await;
 
         let output_tensors = match execution.output_tensors {
             Some(ref oa) => oa,
@@ -384,11 +533,7 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
             }
         };
 
-        let io = InferenceOutput {
-            result: Status::Success,
-            tensor: tensor.to_owned(),
-        };
-        Ok(io)
+        Ok(tensor.to_owned())
     }
 
     /// remove model state
@@ -400,7 +545,79 @@ impl<'a> InferenceEngine for TfLiteEngine<'a> {
     }
 }
 
-pub async fn f32_vec_to_bytes(data: Vec<f32>) -> Vec<u8> {
+/// Guards the copy into an input tensor, which must match the data in its length.
+fn check_length(name: &str, expected: usize, actual: usize) -> InferenceResult<()> {
+    if expected != actual {
+        log::error!(
+            "set_input() - input tensor '{}' expects {} bytes, but got {}",
+            name,
+            expected,
+            actual
+        );
+        return Err(InferenceError::CorruptInputTensor);
+    }
+
+    Ok(())
+}
+
+/// Names of the interpreter's tensors with given indices.
+fn tensor_names(
+    interpreter: &Interpreter<'_, BuiltinOpResolver>,
+    tensor_indices: &[TensorIndex],
+) -> InferenceResult<Vec<String>> {
+    tensor_indices
+        .iter()
+        .map(|&ti| match interpreter.tensor_info(ti) {
+            Some(info) => Ok(info.name),
+            None => {
+                log::error!("tensor_names() - info for tensor {} could not be evaluated", ti);
+                Err(InferenceError::RuntimeError)
+            }
+        })
+        .collect()
+}
+
+/// Names, shapes and data types of the interpreter's tensors with given indices.
+fn tensor_infos(
+    interpreter: &Interpreter<'_, BuiltinOpResolver>,
+    tensor_indices: &[TensorIndex],
+) -> InferenceResult<Vec<TensorInfo>> {
+    tensor_indices
+        .iter()
+        .map(|&ti| match interpreter.tensor_info(ti) {
+            Some(info) => Ok(TensorInfo {
+                name: info.name,
+                shape: Some(
+                    info.dims
+                        .iter()
+                        .map(|&d| DimensionInfo::Fixed(d as u32))
+                        .collect(),
+                ),
+                dtype: data_type_of(info.element_kind),
+            }),
+            None => {
+                log::error!("tensor_infos() - info for tensor {} could not be evaluated", ti);
+                Err(InferenceError::RuntimeError)
+            }
+        })
+        .collect()
+}
+
+/// Maps a TfLite element kind onto the corresponding `wamli:ml/types.data-type`.
+fn data_type_of(kind: ElementKind) -> Option<DataType> {
+    match kind {
+        ElementKind::kTfLiteUInt8 => Some(DataType::U8),
+        ElementKind::kTfLiteInt8 => Some(DataType::S8),
+        ElementKind::kTfLiteInt16 => Some(DataType::S16),
+        ElementKind::kTfLiteInt32 => Some(DataType::S32),
+        ElementKind::kTfLiteInt64 => Some(DataType::S64),
+        ElementKind::kTfLiteFloat16 => Some(DataType::F16),
+        ElementKind::kTfLiteFloat32 => Some(DataType::F32),
+        _ => None,
+    }
+}
+
+pub fn f32_vec_to_bytes(data: Vec<f32>) -> Vec<u8> {
     let sum: f32 = data.iter().sum();
     log::debug!(
         "f32_vec_to_bytes() - flattened output tensor contains {} elements with sum {}",
@@ -2,22 +2,25 @@ use crate::engine::{
     DataType, ExecutionTarget, Graph, GraphEncoding, GraphExecutionContext, InferenceEngine,
     InferenceError, InferenceResult, Tensor,
 };
+use crate::{DimensionInfo, TensorInfo};
 use anyhow::Context;
 use async_trait::async_trait;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use ndarray::Array;
 use std::{
-    collections::{btree_map::Keys, BTreeMap},
+    borrow::Cow,
+    collections::BTreeMap,
     io::Cursor,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tract_data::internal::tract_smallvec::SmallVec;
 use tract_onnx::prelude::TValue; //__CB__NEW
 use tract_onnx::{
     prelude::{Graph as TractGraph, Tensor as TractTensor, *},
-    tract_hir::infer::InferenceOp,
+    tract_core::ops::Op,
+    tract_hir::infer::{Factoid, GenericFactoid, InferenceOp},
 };
+use tract_nnef;
 use tract_tensorflow;
+use tract_tflite;
 // use core::slice::SlicePattern;
 
 // use wasmcloud_interface_mlinference::{
@@ -27,28 +30,201 @@ use tract_tensorflow;
 use tract_onnx::prelude::tract_data::internal::tract_smallvec::alloc::rc::Rc;
 use tract_onnx::prelude::tract_data::internal::tract_smallvec::alloc::sync::Arc;
 
+/// Datum type and shape of each input tensor
+pub type InputFacts = Vec<(DatumType, Vec<TDim>)>;
+
+/// Name of the symbol a declared batch axis is compiled with
+const BATCH_SYMBOL: &str = "N";
+
+/// Optimized and runnable model
+pub type TractPlan = TypedRunnableModel<TypedModel>;
+
+/// A model as parsed from its encoding. ONNX and TensorFlow models are parsed
+/// into an inference model, whose facts are completed once the inputs are
+/// known. TfLite and NNEF models declare all of their facts and are typed right away.
+#[derive(Clone, Debug)]
+pub enum TractModel {
+    Inference(InferenceModel),
+    Typed(TypedModel),
+}
+
+impl TractModel {
+    pub fn inputs(&self) -> &[OutletId] {
+        match self {
+            TractModel::Inference(model) => &model.inputs,
+            TractModel::Typed(model) => &model.inputs,
+        }
+    }
+
+    pub fn input_names(&self) -> Vec<String> {
+        match self {
+            TractModel::Inference(model) => outlet_names(model, &model.inputs),
+            TractModel::Typed(model) => outlet_names(model, &model.inputs),
+        }
+    }
+
+    pub fn output_names(&self) -> Vec<String> {
+        match self {
+            TractModel::Inference(model) => outlet_names(model, &model.outputs),
+            TractModel::Typed(model) => outlet_names(model, &model.outputs),
+        }
+    }
+
+    pub fn input_infos(&self) -> InferenceResult<Vec<TensorInfo>> {
+        match self {
+            TractModel::Inference(model) => tensor_infos(model, &model.inputs),
+            TractModel::Typed(model) => typed_tensor_infos(model, &model.inputs),
+        }
+    }
+
+    /// Quantized outputs, which only typed models declare, are reported as `F32`,
+    /// since they are dequantized, see `to_wamli_tensor()`.
+    pub fn output_infos(&self) -> InferenceResult<Vec<TensorInfo>> {
+        let mut infos = match self {
+            TractModel::Inference(model) => tensor_infos(model, &model.outputs)?,
+            TractModel::Typed(model) => typed_tensor_infos(model, &model.outputs)?,
+        };
+
+        if let TractModel::Typed(model) = self {
+            for (info, &outlet) in infos.iter_mut().zip(model.outputs.iter()) {
+                if model.outlet_fact(outlet)?.datum_type.is_quantized() {
+                    info.dtype = Some(DataType::F32);
+                }
+            }
+        }
+
+        Ok(infos)
+    }
+
+    /// The quantized datum type of each input, `None` for unquantized inputs.
+    pub fn input_quantization(&self) -> InferenceResult<Vec<Option<DatumType>>> {
+        match self {
+            TractModel::Inference(model) => Ok(model.inputs.iter().map(|_| None).collect()),
+            TractModel::Typed(model) => model
+                .inputs
+                .iter()
+                .map(|&outlet| {
+                    let datum_type = model.outlet_fact(outlet)?.datum_type;
+                    Ok(Some(datum_type).filter(|dt| dt.is_quantized()))
+                })
+                .collect(),
+        }
+    }
+
+    /// Optimizes the model for the given input facts, see `decluttered()`.
+    pub fn optimized(&self, input_facts: &InputFacts) -> InferenceResult<TypedModel> {
+        Ok(self.decluttered(input_facts)?.into_optimized()?)
+    }
+
+    /// Types and simplifies the model for the given input facts, without any of the
+    /// platform specific optimizations. Typed models already come with their input
+    /// facts, which are kept as they are.
+    pub fn decluttered(&self, input_facts: &InputFacts) -> InferenceResult<TypedModel> {
+        match self {
+            TractModel::Inference(model) => {
+                // Some ONNX models don't specify their input tensor
+                // shapes completely, so we can only call `.into_optimized()` after we
+                // have set the input tensor shapes.
+                let mut model = model.clone();
+                for (index, (datum_type, shape)) in input_facts.iter().enumerate() {
+                    model.set_input_fact(
+                        index,
+                        InferenceFact::dt_shape(*datum_type, shape.clone()),
+                    )?;
+                }
+
+                Ok(model.into_typed()?.into_decluttered()?)
+            }
+            TractModel::Typed(model) => Ok(model.clone().into_decluttered()?),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TractSession {
-    pub graph: TractGraph<InferenceFact, Box<dyn InferenceOp>>,
-    pub encoding: GraphEncoding,
-    pub input_tensors: Option<Vec<Arc<TractTensor>>>,
+    pub graph: TractModel,
+    /// Axis of the inputs which holds the batch, compiled as symbolic dimension
+    pub batch_axis: Option<usize>,
+    /// The plan optimized for the most recent input facts. It is reused
+    /// as long as subsequent inputs come with the very same facts.
+    pub plan: Option<(InputFacts, Arc<TractPlan>)>,
+    /// Inputs set since the last computation, one slot per input of the model.
+    /// They are taken by `compute()`, such that no request sees those of another.
+    pub input_tensors: Vec<Option<Arc<TractTensor>>>,
     // pub input_tensors: Option<TVec<TValue>>,
     // pub output_tensors: Option<SmallVec<[TValue;4]>>,
     pub output_tensors: Option<Vec<Arc<TractTensor>>>,
 }
 
 impl TractSession {
-    pub fn with_graph(
-        graph: TractGraph<InferenceFact, Box<dyn InferenceOp>>,
-        encoding: GraphEncoding,
-    ) -> Self {
+    pub fn with_graph(graph: TractModel) -> Self {
         Self {
             graph,
-            encoding,
-            input_tensors: None,
+            batch_axis: None,
+            plan: None,
+            input_tensors: Vec::new(),
             output_tensors: None,
         }
     }
+
+    /// Returns the plan for the given input facts. The graph is only
+    /// (re-)optimized in case the facts differ from the cached plan's.
+    pub fn plan_for(&mut self, input_facts: InputFacts) -> InferenceResult<Arc<TractPlan>> {
+        let input_facts = self.compiled_facts(input_facts);
+
+        if let Some((ref cached_facts, ref plan)) = self.plan {
+            if *cached_facts == input_facts {
+                return Ok(Arc::clone(plan));
+            }
+        }
+
+        log::debug!(
+            "plan_for() - optimizing model for input facts {:?}",
+            &input_facts
+        );
+
+        let plan = Arc::new(self.graph.optimized(&input_facts)?.into_runnable()?);
+        self.plan = Some((input_facts, Arc::clone(&plan)));
+
+        Ok(plan)
+    }
+
+    /// Whether the model takes any batch size along `axis`. Inference models are
+    /// compiled accordingly, typed models are run with the facts they come with,
+    /// hence only take any size if the axis is a symbolic dimension already.
+    pub fn supports_batch_axis(&self, axis: usize) -> bool {
+        match self.graph {
+            TractModel::Inference(_) => true,
+            TractModel::Typed(ref model) => model.inputs.iter().all(|&outlet| {
+                model.outlet_fact(outlet).is_ok_and(|fact| {
+                    fact.shape.get(axis).is_none_or(|dim| dim.to_i64().is_err())
+                })
+            }),
+        }
+    }
+
+    /// The facts the model is actually compiled for. A declared batch axis is
+    /// kept symbolic, such that one plan serves any batch size. Inputs whose rank
+    /// does not cover the batch axis hold no batch and keep their facts. Typed
+    /// models are compiled for the facts they come with, regardless of the inputs,
+    /// which is why they only accept a batch axis they declare as symbolic.
+    pub fn compiled_facts(&self, mut input_facts: InputFacts) -> InputFacts {
+        match self.graph {
+            TractModel::Typed(_) => Vec::new(),
+            TractModel::Inference(ref model) => {
+                if let Some(axis) = self.batch_axis {
+                    let batch = model.symbols.sym(BATCH_SYMBOL).to_dim();
+                    for (_, shape) in input_facts.iter_mut() {
+                        if let Some(dim) = shape.get_mut(axis) {
+                            *dim = batch.clone();
+                        }
+                    }
+                }
+
+                input_facts
+            }
+        }
+    }
 }
 
 #[derive(Default, Clone)]
@@ -56,23 +232,19 @@ pub struct TractEngine {
     state: Arc<RwLock<ModelState>>,
 }
 
+/// The engine's state is only locked for looking up or (de-)registering
+/// sessions. Each session owns its lock, so that different models are
+/// computed in parallel.
 #[derive(Default)]
 pub struct ModelState {
-    executions: BTreeMap<GraphExecutionContext, TractSession>,
+    executions: BTreeMap<GraphExecutionContext, Arc<Mutex<TractSession>>>,
+    /// raw models, which are only kept until they are parsed
     models: BTreeMap<Graph, Vec<u8>>,
-}
-
-impl ModelState {
-    /// Helper function that returns the key that is supposed to be inserted next.
-    pub fn key<K: Into<u32> + From<u32> + Copy, V>(&self, keys: Keys<K, V>) -> K {
-        match keys.last() {
-            Some(&k) => {
-                let last: u32 = k.into();
-                K::from(last + 1)
-            }
-            None => K::from(0),
-        }
-    }
+    /// number of models loaded so far, the keys of raw models are never reused
+    models_loaded: Graph,
+    /// number of execution contexts initialized so far, their keys are never reused
+    /// either, such that a request holding a dropped context never hits another model
+    contexts_initialized: GraphExecutionContext,
 }
 
 #[async_trait]
@@ -81,7 +253,8 @@ impl InferenceEngine for TractEngine {
     async fn load(&self, model: &[u8]) -> InferenceResult<Graph> {
         let model_bytes = model.to_vec();
         let mut state = self.state.write().await;
-        let graph = state.key(state.models.keys());
+        let graph = state.models_loaded;
+        state.models_loaded += 1;
 
         log::debug!(
             "load() - inserting graph: {:#?} with size {:#?}",
@@ -126,7 +299,9 @@ impl InferenceEngine for TractEngine {
             return Err(InferenceError::UnsupportedExecutionTarget);
         }
 
-        let mut state = self.state.write().await;
+        // parsing only requires read access, such that
+        // other models remain available in the meantime
+        let state = self.state.read().await;
         let mut model_bytes = match state.models.get(&graph) {
             Some(mb) => Cursor::new(mb),
             None => {
@@ -139,13 +314,32 @@ impl InferenceEngine for TractEngine {
         };
 
         let model = match encoding {
-            GraphEncoding::Onnx => tract_onnx::onnx()
-                .model_for_read(&mut model_bytes)
-                .context("failed to get model for read")?,
+            GraphEncoding::Onnx => TractModel::Inference(
+                tract_onnx::onnx()
+                    .model_for_read(&mut model_bytes)
+                    .context("failed to get model for read")?,
+            ),
+
+            GraphEncoding::Tensorflow => TractModel::Inference(
+                tract_tensorflow::tensorflow()
+                    .model_for_read(&mut model_bytes)
+                    .context("failed to get model for read")?,
+            ),
+
+            GraphEncoding::TfLite => TractModel::Typed(
+                tract_tflite::tflite()
+                    .model_for_read(&mut model_bytes)
+                    .context("failed to get model for read")?,
+            ),
 
-            GraphEncoding::Tensorflow => tract_tensorflow::tensorflow()
-                .model_for_read(&mut model_bytes)
-                .context("failed to get model for read")?,
+            // a (gzipped) tar archive, as written by `tract --nnef-tar`, which may
+            // contain tract's own operators as well as those of the ONNX extension
+            GraphEncoding::Nnef => TractModel::Typed(
+                tract_nnef::nnef()
+                    .with_onnx()
+                    .model_for_read(&mut model_bytes)
+                    .context("failed to get model for read")?,
+            ),
 
             _ => {
                 log::error!(
@@ -156,7 +350,14 @@ impl InferenceEngine for TractEngine {
             }
         };
 
-        let gec = state.key(state.executions.keys());
+        drop(state);
+
+        let mut state = self.state.write().await;
+        let gec = state.contexts_initialized;
+        state.contexts_initialized += 1;
+
+        // once parsed, the raw model is not needed anymore
+        state.models.remove(&graph);
 
         log::debug!(
             "init_execution_context() - inserting graph execution context: {:#?}",
@@ -165,7 +366,7 @@ impl InferenceEngine for TractEngine {
 
         state
             .executions
-            .insert(gec, TractSession::with_graph(model, encoding.to_owned()));
+            .insert(gec, Arc::new(Mutex::new(TractSession::with_graph(model))));
 
         Ok(gec)
     }
@@ -184,101 +385,98 @@ impl InferenceEngine for TractEngine {
             tensor
         );
 
-        let mut state = self.state.write().await;
-        let execution = match state.executions.get_mut(&context) {
-            Some(s) => s,
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
             None => {
                 log::error!(
                     "set_input() - cannot find session in state with context {:#?}",
                     context
                 );
-                return Err(InferenceError::RuntimeError);
+                return Err(InferenceError::ContextNotFound(context));
             }
         };
+        let mut execution = session.lock().await;
 
         let shape: Vec<usize> = tensor.shape.iter().map(|x| *x as usize).collect();
+        let datum_type = datum_type_from(&tensor.dtype)?;
 
-        execution.graph.set_input_fact(
-            index as usize,
-            InferenceFact::dt_shape(f32::datum_type(), shape.clone()),
-        )?;
-
-        // let data: Vec<f32> = bytes_to_f32_vec(tensor.data.as_slice().to_vec()).await?;
-        let data: Vec<f32> = bytes_to_f32_vec(tensor.data.to_vec()).await?;
-
-        let input: TractTensor = Array::from_shape_vec(shape, data)
-            .map_err(|e| InferenceError::ReShapeError(e.to_string()))?
-            .into();
+        let mut input: TractTensor = bytes_to_tensor(datum_type, &shape, &tensor.data)?;
+        if let Some(&quantized) = execution.graph.input_quantization()?.get(index as usize) {
+            quantize_as(&mut input, quantized);
+        }
 
-        match execution.input_tensors {
-            Some(ref mut input_arrays) => {
-                // __CB__2022-03-10 re-evaluate next line
-                input_arrays.clear();
-                input_arrays.push(input.into());
+        // inputs are kept at their index such that models with multiple
+        // inputs can be fed by consecutive calls of set_input()
+        let inputs = execution.graph.inputs().len();
+        let input_arrays = &mut execution.input_tensors;
+        input_arrays.resize(inputs, None);
 
-                log::debug!(
-                    "set_input() - input arrays now contains {} items",
-                    input_arrays.len(),
-                );
-            }
+        match input_arrays.get_mut(index as usize) {
+            Some(slot) => *slot = Some(input.into()),
             None => {
-                execution.input_tensors = Some(vec![input.into()]);
+                log::error!(
+                    "set_input() - model has {} inputs, index {} is out of range",
+                    inputs,
+                    index
+                );
+                return Err(InferenceError::CorruptInputTensor);
             }
-        };
+        }
+
+        log::debug!(
+            "set_input() - {} of {} inputs are set",
+            input_arrays.iter().filter(|slot| slot.is_some()).count(),
+            inputs
+        );
+
         Ok(())
     }
 
     /// compute()
     async fn compute(&self, context: GraphExecutionContext) -> InferenceResult<()> {
-        let mut state = self.state.write().await;
-        let execution = match state.executions.get_mut(&context) {
-            Some(s) => s,
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
             None => {
                 log::error!(
                     "compute() - cannot find session in state with context {:#?}",
                     context
                 );
 
-                return Err(InferenceError::RuntimeError);
+                return Err(InferenceError::ContextNotFound(context));
             }
         };
+        let mut execution = session.lock().await;
+
+        // the inputs are consumed, the next computation requires inputs of its own
+        let input_slots = std::mem::take(&mut execution.input_tensors);
+        let inputs = execution.graph.inputs().len();
 
-        // TODO
-        //
-        // There are two `.clone()` calls here that could prove
-        // to be *very* ineficient, one in getting the input tensors,
-        // the other in making the model runnable.
-        // let input_tensors: Vec<Arc<TractTensor>> = execution
-        // // let input_tensors = execution
-        //     .input_tensors
-        //     .as_ref()
-        //     .unwrap_or(&vec![])
-        //     .clone()
-        //     .into_iter()
-        //     .collect();
-
-        let input_tensors: SmallVec<[TValue; 4]> = execution
-            .input_tensors
-            .as_ref()
-            .unwrap_or(&vec![])
-            .iter() // Use `iter` instead of `into_iter` to avoid consuming the vector
-            .map(|arc_tensor| TValue::Const(arc_tensor.clone())) // Wrap each `Arc<Tensor>` in `TValue::Const`
-            .collect(); // Collect into `SmallVec<[TValue; 4]>`
+        if input_slots.len() != inputs || input_slots.iter().any(Option::is_none) {
+            log::error!(
+                "compute() - model has {} inputs, but only {} are set",
+                inputs,
+                input_slots.iter().filter(|slot| slot.is_some()).count()
+            );
+            return Err(InferenceError::CorruptInputTensor);
+        }
+
+        let input_tensors: SmallVec<[TValue; 4]> = input_slots
+            .into_iter()
+            .flatten()
+            .map(TValue::Const)
+            .collect();
 
         log::debug!(
             "compute() - input tensors contains {} elements",
             input_tensors.len()
         );
 
-        // Some ONNX models don't specify their input tensor
-        // shapes completely, so we can only call `.into_optimized()` after we
-        // have set the input tensor shapes.
-        let output_tensors = execution
-            .graph
-            .clone()
-            .into_optimized()?
-            .into_runnable()?
-            .run(input_tensors)?;
+        let input_facts: InputFacts = input_tensors
+            .iter()
+            .map(|t| (t.datum_type(), t.shape().iter().map(|&d| d.to_dim()).collect()))
+            .collect();
+
+        let output_tensors = execution.plan_for(input_facts)?.run(input_tensors)?;
 
         log::debug!(
             "compute() - output tensors contains {} elements",
@@ -310,24 +508,102 @@ impl InferenceEngine for TractEngine {
         Ok(())
     }
 
+    /// input_names
+    async fn input_names(&self, context: GraphExecutionContext) -> InferenceResult<Vec<String>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "input_names() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
+
+        Ok(execution.graph.input_names())
+    }
+
+    /// output_names
+    async fn output_names(&self, context: GraphExecutionContext) -> InferenceResult<Vec<String>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "output_names() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
+
+        Ok(execution.graph.output_names())
+    }
+
+    /// input_infos
+    async fn input_infos(
+        &self,
+        context: GraphExecutionContext,
+    ) -> InferenceResult<Vec<TensorInfo>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "input_infos() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
+
+        execution.graph.input_infos()
+    }
+
+    /// output_infos
+    async fn output_infos(
+        &self,
+        context: GraphExecutionContext,
+    ) -> InferenceResult<Vec<TensorInfo>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "output_infos() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let execution = session.lock().await;
+
+        execution.graph.output_infos()
+    }
+
     /// get_output
     async fn get_output(
         &self,
         context: GraphExecutionContext,
         index: u32,
     ) -> InferenceResult<Tensor> {
-        let state = self.state.read().await;
-        let execution = match state.executions.get(&context) {
-            Some(s) => s,
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
             None => {
                 log::error!(
                     "compute() - cannot find session in state with context {:#?}",
                     context
                 );
 
-                return Err(InferenceError::RuntimeError);
+                return Err(InferenceError::ContextNotFound(context));
             }
         };
+        let execution = session.lock().await;
 
         let output_tensors = match execution.output_tensors {
             Some(ref oa) => oa,
@@ -352,20 +628,167 @@ impl InferenceEngine for TractEngine {
             }
         };
 
-        let bytes = f32_array_to_bytes(tensor.as_slice().unwrap()).await;
+        to_wamli_tensor(tensor)
+    }
 
-        let tensor_out = Tensor {
-            dtype: DataType::F32,
-            shape: tensor
-                .shape()
-                .iter()
-                .cloned()
-                .map(|i| i as u32)
-                .collect::<Vec<u32>>(),
-            data: bytes.into(),
+    /// infer
+    async fn infer(
+        &self,
+        context: GraphExecutionContext,
+        inputs: Vec<(u32, Tensor)>,
+    ) -> InferenceResult<Vec<Tensor>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "infer() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
         };
 
-        Ok(tensor_out)
+        let quantization = session.lock().await.graph.input_quantization()?;
+        let mut input_slots: Vec<Option<TractTensor>> = quantization.iter().map(|_| None).collect();
+
+        for (index, tensor) in inputs.iter() {
+            let shape: Vec<usize> = tensor.shape.iter().map(|x| *x as usize).collect();
+            let datum_type = datum_type_from(&tensor.dtype)?;
+
+            match input_slots.get_mut(*index as usize) {
+                Some(slot) if slot.is_some() => {
+                    log::error!("infer() - input {} is assigned more than once", index);
+                    return Err(InferenceError::CorruptInputTensor);
+                }
+                Some(slot) => {
+                    let mut input = bytes_to_tensor(datum_type, &shape, &tensor.data)?;
+                    quantize_as(&mut input, quantization[*index as usize]);
+                    *slot = Some(input);
+                }
+                None => {
+                    log::error!(
+                        "infer() - model has {} inputs, index {} is out of range",
+                        input_slots.len(),
+                        index
+                    );
+                    return Err(InferenceError::CorruptInputTensor);
+                }
+            }
+        }
+
+        let mut tensors: Vec<TractTensor> = Vec::with_capacity(input_slots.len());
+        for (index, slot) in input_slots.into_iter().enumerate() {
+            match slot {
+                Some(tensor) => tensors.push(tensor),
+                None => {
+                    log::error!("infer() - no tensor provided for input {}", index);
+                    return Err(InferenceError::CorruptInputTensor);
+                }
+            }
+        }
+
+        let input_facts: InputFacts = tensors
+            .iter()
+            .map(|t| (t.datum_type(), t.shape().iter().map(|&d| d.to_dim()).collect()))
+            .collect();
+
+        // the session is only locked while looking up (or building) the plan,
+        // the plan itself may be run by concurrent requests in parallel
+        let plan = session.lock().await.plan_for(input_facts)?;
+
+        let input_tensors: TVec<TValue> = tensors.into_iter().map(|t| t.into_tvalue()).collect();
+
+        let output_tensors = plan.run(input_tensors)?;
+
+        log::debug!(
+            "infer() - output tensors contains {} elements",
+            output_tensors.len()
+        );
+
+        output_tensors.iter().map(|t| to_wamli_tensor(t)).collect()
+    }
+
+    /// compile_to_nnef
+    async fn compile_to_nnef(
+        &self,
+        context: GraphExecutionContext,
+        input: Option<(DataType, Vec<u32>)>,
+    ) -> InferenceResult<Option<Vec<u8>>> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "compile_to_nnef() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+
+        let input_facts: InputFacts = match input {
+            Some((dtype, shape)) => vec![(
+                datum_type_from(&dtype)?,
+                shape.iter().map(|&dim| (dim as usize).to_dim()).collect(),
+            )],
+            None => vec![],
+        };
+
+        // the session is only locked for copying the graph, not while compiling
+        let (graph, input_facts) = {
+            let execution = session.lock().await;
+            (execution.graph.clone(), execution.compiled_facts(input_facts))
+        };
+
+        // compiling is CPU-bound, it must not stall the runtime
+        let compiled = tokio::task::spawn_blocking(move || -> InferenceResult<Vec<u8>> {
+            let model = graph.decluttered(&input_facts)?;
+
+            Ok(tract_nnef::nnef()
+                .with_onnx()
+                .write_to_tar(&model, Vec::new())?)
+        })
+        .await;
+
+        match compiled {
+            Ok(archive) => Ok(Some(archive?)),
+            Err(error) => {
+                log::error!("compile_to_nnef() - compilation did not complete: {}", error);
+                Err(InferenceError::RuntimeError)
+            }
+        }
+    }
+
+    /// set_batch_axis
+    async fn set_batch_axis(
+        &self,
+        context: GraphExecutionContext,
+        axis: usize,
+    ) -> InferenceResult<()> {
+        let session = match self.state.read().await.executions.get(&context) {
+            Some(s) => Arc::clone(s),
+            None => {
+                log::error!(
+                    "set_batch_axis() - cannot find session in state with context {:#?}",
+                    context
+                );
+
+                return Err(InferenceError::ContextNotFound(context));
+            }
+        };
+        let mut execution = session.lock().await;
+
+        if !execution.supports_batch_axis(axis) {
+            return Err(InferenceError::FixedBatchSize(axis));
+        }
+
+        if execution.batch_axis != Some(axis) {
+            execution.batch_axis = Some(axis);
+            execution.plan = None;
+        }
+
+        Ok(())
     }
 
     /// remove model state
@@ -377,24 +800,259 @@ impl InferenceEngine for TractEngine {
     }
 }
 
-pub type Result<T> = std::io::Result<T>;
+/// Names of the given outlets. Outlets are named by their label, if present,
+/// and by the name of the node they belong to otherwise.
+fn outlet_names<F, O>(graph: &TractGraph<F, O>, outlets: &[OutletId]) -> Vec<String>
+where
+    F: Fact + Clone + 'static,
+    O: std::fmt::Debug + std::fmt::Display + AsRef<dyn Op> + AsMut<dyn Op> + Clone + 'static,
+{
+    outlets
+        .iter()
+        .map(|&outlet| match graph.outlet_label(outlet) {
+            Some(label) => label.to_owned(),
+            None => graph.node(outlet.node).name.clone(),
+        })
+        .collect()
+}
 
-pub async fn bytes_to_f32_vec(data: Vec<u8>) -> Result<Vec<f32>> {
-    data.chunks(4)
-        .map(|c| {
-            let mut rdr = Cursor::new(c);
-            rdr.read_f32::<LittleEndian>()
+/// Describes the given outlets by whatever tract can infer from the graph alone.
+fn tensor_infos(
+    graph: &TractGraph<InferenceFact, Box<dyn InferenceOp>>,
+    outlets: &[OutletId],
+) -> InferenceResult<Vec<TensorInfo>> {
+    // The analysis propagates the facts through the graph, which is what
+    // reveals the outputs' shapes in the first place. It runs on a copy,
+    // such that the session's graph stays as loaded.
+    let mut graph = graph.clone();
+    if let Err(error) = graph.analyse(true) {
+        log::debug!("tensor_infos() - analysis of graph is incomplete: {}", error);
+    }
+
+    outlet_names(&graph, outlets)
+        .into_iter()
+        .zip(outlets)
+        .map(|(name, &outlet)| {
+            let fact = graph.outlet_fact(outlet)?;
+
+            Ok(TensorInfo {
+                name,
+                shape: shape_info(fact),
+                dtype: fact
+                    .datum_type
+                    .concretize()
+                    .and_then(|datum_type| data_type_from(datum_type).ok()),
+            })
         })
         .collect()
 }
 
-pub async fn f32_array_to_bytes(values: &[f32]) -> Vec<u8> {
-    let mut wtr = Vec::with_capacity(values.len() * 4);
-    for val in values.iter() {
-        // unwrap ok because buf is pre-allocated and won't error
-        wtr.write_f32::<LittleEndian>(*val).unwrap();
+/// Dimensions of an inferred fact, or `None` in case not even its rank is known.
+fn shape_info(fact: &InferenceFact) -> Option<Vec<DimensionInfo>> {
+    if fact.shape.is_open() {
+        return None;
+    }
+
+    Some(
+        fact.shape
+            .dims()
+            .map(|dim| match dim {
+                GenericFactoid::Only(dim) => dimension_info(dim),
+                GenericFactoid::Any => DimensionInfo::Symbolic("?".to_string()),
+            })
+            .collect(),
+    )
+}
+
+/// Describes the given outlets of a typed model, whose facts are complete.
+fn typed_tensor_infos(
+    graph: &TypedModel,
+    outlets: &[OutletId],
+) -> InferenceResult<Vec<TensorInfo>> {
+    outlet_names(graph, outlets)
+        .into_iter()
+        .zip(outlets)
+        .map(|(name, &outlet)| {
+            let fact = graph.outlet_fact(outlet)?;
+
+            Ok(TensorInfo {
+                name,
+                shape: Some(fact.shape.iter().map(dimension_info).collect()),
+                dtype: data_type_from(fact.datum_type).ok(),
+            })
+        })
+        .collect()
+}
+
+fn dimension_info(dim: &TDim) -> DimensionInfo {
+    match dim.to_i64() {
+        Ok(size) => DimensionInfo::Fixed(size as u32),
+        Err(_) => DimensionInfo::Symbolic(dim.to_string()),
+    }
+}
+
+/// Reinterprets raw input data as the quantized type the model expects,
+/// e.g. the `u8` pixels of an image as `QU8` with the model's zero point and scale.
+fn quantize_as(tensor: &mut TractTensor, quantized: Option<DatumType>) {
+    if let Some(datum_type) = quantized {
+        if tensor.datum_type() == datum_type.unquantized() {
+            // the storage type is the very same, only the interpretation differs
+            unsafe { tensor.set_datum_type(datum_type) };
+        }
+    }
+}
+
+/// Converts a tract tensor into a `wamli:ml/types.tensor`.
+/// Quantized tensors are dequantized to `F32`.
+fn to_wamli_tensor(tensor: &TractTensor) -> InferenceResult<Tensor> {
+    let tensor = match tensor.datum_type().is_quantized() {
+        true => tensor.cast_to::<f32>()?,
+        false => Cow::Borrowed(tensor),
+    };
+    let (dtype, bytes) = tensor_to_bytes(&tensor)?;
+
+    Ok(Tensor {
+        dtype,
+        shape: tensor
+            .shape()
+            .iter()
+            .cloned()
+            .map(|i| i as u32)
+            .collect::<Vec<u32>>(),
+        data: bytes.into(),
+    })
+}
+
+/// Maps a `wamli:ml/types.data-type` onto the corresponding tract `DatumType`.
+///
+/// Tract has no 128-bit types, hence `U128`, `S128` and `F128` are rejected
+/// just like `NA`.
+pub fn datum_type_from(dtype: &DataType) -> InferenceResult<DatumType> {
+    match dtype {
+        DataType::U8 => Ok(DatumType::U8),
+        DataType::U16 => Ok(DatumType::U16),
+        DataType::U32 => Ok(DatumType::U32),
+        DataType::U64 => Ok(DatumType::U64),
+        DataType::S8 => Ok(DatumType::I8),
+        DataType::S16 => Ok(DatumType::I16),
+        DataType::S32 => Ok(DatumType::I32),
+        DataType::S64 => Ok(DatumType::I64),
+        DataType::F16 => Ok(DatumType::F16),
+        DataType::F32 => Ok(DatumType::F32),
+        DataType::F64 => Ok(DatumType::F64),
+        DataType::U128 | DataType::S128 | DataType::F128 | DataType::Na => {
+            log::error!(
+                "datum_type_from() - data type '{:?}' is not supported by tract",
+                dtype
+            );
+            Err(InferenceError::UnsupportedDataType(format!("{:?}", dtype)))
+        }
+    }
+}
+
+/// Maps a tract `DatumType` onto the corresponding `wamli:ml/types.data-type`.
+///
+/// Quantized types are reported by their storage type, booleans as `U8`.
+pub fn data_type_from(datum_type: DatumType) -> InferenceResult<DataType> {
+    match datum_type.unquantized() {
+        DatumType::Bool | DatumType::U8 => Ok(DataType::U8),
+        DatumType::U16 => Ok(DataType::U16),
+        DatumType::U32 => Ok(DataType::U32),
+        DatumType::U64 => Ok(DataType::U64),
+        DatumType::I8 => Ok(DataType::S8),
+        DatumType::I16 => Ok(DataType::S16),
+        DatumType::I32 => Ok(DataType::S32),
+        DatumType::I64 => Ok(DataType::S64),
+        DatumType::F16 => Ok(DataType::F16),
+        DatumType::F32 => Ok(DataType::F32),
+        DatumType::F64 => Ok(DataType::F64),
+        _ => {
+            log::error!(
+                "data_type_from() - tract datum type '{:?}' has no wamli counterpart",
+                datum_type
+            );
+            Err(InferenceError::UnsupportedDataType(format!("{:?}", datum_type)))
+        }
+    }
+}
+
+/// Builds a tract tensor of given type and shape from little endian bytes.
+pub fn bytes_to_tensor(
+    datum_type: DatumType,
+    shape: &[usize],
+    data: &[u8],
+) -> InferenceResult<TractTensor> {
+    match datum_type {
+        DatumType::U8 => vec_to_tensor(shape, data.to_vec()),
+        DatumType::U16 => vec_to_tensor(shape, le_bytes_to_vec(data, u16::from_le_bytes)?),
+        DatumType::U32 => vec_to_tensor(shape, le_bytes_to_vec(data, u32::from_le_bytes)?),
+        DatumType::U64 => vec_to_tensor(shape, le_bytes_to_vec(data, u64::from_le_bytes)?),
+        DatumType::I8 => vec_to_tensor(shape, le_bytes_to_vec(data, i8::from_le_bytes)?),
+        DatumType::I16 => vec_to_tensor(shape, le_bytes_to_vec(data, i16::from_le_bytes)?),
+        DatumType::I32 => vec_to_tensor(shape, le_bytes_to_vec(data, i32::from_le_bytes)?),
+        DatumType::I64 => vec_to_tensor(shape, le_bytes_to_vec(data, i64::from_le_bytes)?),
+        DatumType::F16 => vec_to_tensor(shape, le_bytes_to_vec(data, f16::from_le_bytes)?),
+        DatumType::F32 => vec_to_tensor(shape, le_bytes_to_vec(data, f32::from_le_bytes)?),
+        DatumType::F64 => vec_to_tensor(shape, le_bytes_to_vec(data, f64::from_le_bytes)?),
+        _ => Err(InferenceError::UnsupportedDataType(format!("{:?}", datum_type))),
+    }
+}
+
+/// Serializes a tract tensor into little endian bytes together with its wamli data type.
+pub fn tensor_to_bytes(tensor: &TractTensor) -> InferenceResult<(DataType, Vec<u8>)> {
+    let dtype = data_type_from(tensor.datum_type())?;
+
+    let bytes = match tensor.datum_type().unquantized() {
+        DatumType::Bool => tensor.as_slice::<bool>()?.iter().map(|&b| b as u8).collect(),
+        DatumType::U8 => tensor.as_slice::<u8>()?.to_vec(),
+        DatumType::U16 => slice_to_le_bytes(tensor.as_slice::<u16>()?, u16::to_le_bytes),
+        DatumType::U32 => slice_to_le_bytes(tensor.as_slice::<u32>()?, u32::to_le_bytes),
+        DatumType::U64 => slice_to_le_bytes(tensor.as_slice::<u64>()?, u64::to_le_bytes),
+        DatumType::I8 => slice_to_le_bytes(tensor.as_slice::<i8>()?, i8::to_le_bytes),
+        DatumType::I16 => slice_to_le_bytes(tensor.as_slice::<i16>()?, i16::to_le_bytes),
+        DatumType::I32 => slice_to_le_bytes(tensor.as_slice::<i32>()?, i32::to_le_bytes),
+        DatumType::I64 => slice_to_le_bytes(tensor.as_slice::<i64>()?, i64::to_le_bytes),
+        DatumType::F16 => slice_to_le_bytes(tensor.as_slice::<f16>()?, f16::to_le_bytes),
+        DatumType::F32 => slice_to_le_bytes(tensor.as_slice::<f32>()?, f32::to_le_bytes),
+        DatumType::F64 => slice_to_le_bytes(tensor.as_slice::<f64>()?, f64::to_le_bytes),
+        _ => {
+            return Err(InferenceError::UnsupportedDataType(format!(
+                "{:?}",
+                tensor.datum_type()
+            )))
+        }
+    };
+
+    Ok((dtype, bytes))
+}
+
+fn vec_to_tensor<T: Datum>(shape: &[usize], values: Vec<T>) -> InferenceResult<TractTensor> {
+    Ok(tract_ndarray::Array::from_shape_vec(shape.to_vec(), values)
+        .map_err(|e| InferenceError::ReShapeError(e.to_string()))?
+        .into())
+}
+
+fn le_bytes_to_vec<T, const N: usize>(
+    data: &[u8],
+    from_le_bytes: fn([u8; N]) -> T,
+) -> InferenceResult<Vec<T>> {
+    if !data.len().is_multiple_of(N) {
+        log::error!(
+            "le_bytes_to_vec() - {} bytes cannot be split into elements of {} bytes",
+            data.len(),
+            N
+        );
+        return Err(InferenceError::CorruptInputTensor);
     }
-    wtr
+
+    Ok(data
+        .chunks_exact(N)
+        .map(|chunk| from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn slice_to_le_bytes<T: Copy, const N: usize>(values: &[T], to_le_bytes: fn(T) -> [u8; N]) -> Vec<u8> {
+    values.iter().flat_map(|&v| to_le_bytes(v)).collect()
 }
 
 // pub async fn f32_vec_to_bytes(data: Vec<f32>) -> Vec<u8> {
@@ -413,3 +1071,52 @@ pub async fn f32_array_to_bytes(values: &[f32]) -> Vec<u8> {
 //     );
 //     result
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed_session(shape: ShapeFact) -> TractSession {
+        let mut model = TypedModel::default();
+        let source = model.add_source("input", f32::fact(shape)).unwrap();
+        model.set_output_outlets(&[source]).unwrap();
+
+        TractSession::with_graph(TractModel::Typed(model))
+    }
+
+    #[test]
+    fn typed_models_only_batch_along_symbolic_axes() {
+        let fixed = typed_session(ShapeFact::from_dims([1.to_dim(), 3.to_dim()]));
+        assert!(!fixed.supports_batch_axis(0));
+
+        let batch = SymbolScope::default().sym("N").to_dim();
+        let symbolic = typed_session(ShapeFact::from_dims([batch, 3.to_dim()]));
+        assert!(symbolic.supports_batch_axis(0));
+        assert!(!symbolic.supports_batch_axis(1));
+    }
+
+    #[tokio::test]
+    async fn infer_rejects_an_input_assigned_twice() {
+        let engine = TractEngine::default();
+        let session = typed_session(ShapeFact::from_dims([1.to_dim(), 3.to_dim()]));
+        engine
+            .state
+            .write()
+            .await
+            .executions
+            .insert(0, Arc::new(Mutex::new(session)));
+
+        let tensor = Tensor {
+            shape: vec![1, 3],
+            dtype: DataType::F32,
+            data: vec![0u8; 12].into(),
+        };
+        let inputs = vec![(0, tensor.clone()), (0, tensor.clone())];
+
+        assert!(matches!(
+            engine.infer(0, inputs).await,
+            Err(InferenceError::CorruptInputTensor)
+        ));
+        assert_eq!(engine.infer(0, vec![(0, tensor)]).await.unwrap().len(), 1);
+    }
+}
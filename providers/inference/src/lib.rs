@@ -1,7 +1,13 @@
+pub mod batching;
+pub mod compile_cache;
 pub mod config;
 pub mod data_loader;
 pub mod engine;
 pub mod provider;
+pub mod routing;
+pub mod shadowing;
+pub mod validation;
+pub mod workers;
 
 wit_bindgen_wrpc::generate!({
     with: {
@@ -11,4 +17,7 @@ wit_bindgen_wrpc::generate!({
 });
 
 pub use crate::exports::wamli::ml::inference::Handler;
-pub use crate::wamli::ml::types::{DataType, MlError, Tensor};
+pub use crate::wamli::ml::types::{
+    DataType, DimensionInfo, LoadState, MlError, ModelInfo, ModelSummary, NamedTensor, Overload,
+    PredictOptions, RouteTarget, RouteVersionStats, ShadowReport, Tensor, TensorInfo,
+};
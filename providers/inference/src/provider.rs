@@ -1,154 +1,1447 @@
+use crate::batching::Batcher;
+use crate::compile_cache::{self, CompileCache};
 use crate::config::{ProviderConfig, CONFIG_URL_KEY, DEFAULT_CONNECT_URL};
-use crate::data_loader::{self, ModelRawData};
+use crate::data_loader::{self, ModelMetadata, ModelRawData};
 use crate::engine::{
-    get_engine, get_or_else_set_engine, Engine, ExecutionTarget, Graph, GraphEncoding,
-    GraphExecutionContext, InferenceFramework, ModelContext, ModelZoo,
+    element_size, get_engine, get_or_else_set_engine, index_of, unix_millis, Engine,
+    ExecutionTarget, Graph, GraphEncoding, GraphExecutionContext, GraphHandle, InferenceFramework,
+    LoadState, LoadedGraph, ModelContext, ModelEntry, ModelId, ModelZoo,
+};
+use crate::routing::Route;
+use crate::shadowing::{self, ShadowStats};
+use crate::validation;
+use crate::workers::{Admission, WorkerPool};
+use crate::{
+    DataType, DimensionInfo, Handler, MlError, ModelInfo, ModelSummary, NamedTensor,
+    PredictOptions, RouteTarget, RouteVersionStats, ShadowReport, Tensor, TensorInfo,
 };
-use crate::{DataType, Handler, MlError, Tensor};
 use anyhow::anyhow;
 use anyhow::Context as _;
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
 use tracing::{debug, info};
 use wasmcloud_provider_sdk::{run_provider, Context, LinkConfig, Provider, ProviderInitConfig, serve_provider_exports, LinkDeleteInfo};
 
-pub struct ModelData {
-    pub model: Vec<u8>,
-    pub metadata: ModelContext,
-}
+pub struct ModelData {
+    pub model: Vec<u8>,
+    pub metadata: ModelContext,
+}
+
+#[derive(Default, Clone)]
+pub struct InferenceProvider {
+    /// map to store the assignments between the respective model
+    /// and corresponding bindle path for each linked actor.
+    /// Models are loaded in the background, each entry tracks its load state
+    /// and requests to models which are not ready yield a not-ready error.
+    // components: Arc<RwLock<HashMap<String, ModelZoo>>>,
+    models: Arc<RwLock<ModelZoo>>,
+
+    config: Arc<RwLock<ProviderConfig>>,
+
+    /// There are the following relevant types:
+    ///     - InferenceFramework
+    ///     - Engine
+    ///     - InferenceEngine
+    ///     - GraphEncoding
+    ///
+    /// InferenceFramework corresponds to an integrated crate.
+    /// An InferenceFramework may support a multitude of Engine
+    /// An InferenceFramework may support a multitude of GraphEncoding.
+    /// Engine is a wrapper of InferenceEngine
+    /// InferenceEngine defines common behavior
+    /// GraphEncoding defines a model's encoding.
+    engines: Arc<RwLock<HashMap<InferenceFramework, Engine>>>,
+
+    /// Most recently requested model of each alias. An alias is registered
+    /// in `models` as well, sharing the graph of the model it points at.
+    aliases: Arc<RwLock<HashMap<String, ModelId>>>,
+
+    /// Routes of logical models onto several models, by their name
+    routes: Arc<RwLock<HashMap<String, Route>>>,
+
+    /// Comparisons of models with their shadow models, by the production model's id
+    shadows: Arc<RwLock<HashMap<ModelId, ShadowStats>>>,
+
+    /// Models which failed to autoload, by their id, which are not retried before
+    /// their backoff passes, see `autoload()`
+    autoload_failures: Arc<RwLock<HashMap<ModelId, AutoloadFailure>>>,
+
+    /// Wakes up requests waiting for a model to finish loading, see `wait_until_loaded()`
+    state_changed: Arc<Notify>,
+
+    /// Graphs loaded into the engines by the digest of their content
+    graphs: Arc<RwLock<HashMap<String, LoadedGraph>>>,
+
+    /// Room reserved for the graphs being loaded, only changed while the lock of
+    /// `graphs` is held, such that limits are checked against both, see `make_room()`
+    reserved: Arc<std::sync::Mutex<ReservedRoom>>,
+
+    /// Batchers of all models for which batching is configured
+    batchers: Arc<RwLock<HashMap<ModelId, Batcher>>>,
+
+    /// Threads running the inferences, started on the first inference, see `workers()`
+    workers: Arc<OnceLock<WorkerPool>>,
+
+    /// All components linked to this provider and their config.
+    linked_from: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
+    /// All components this provider is linked to and their config
+    linked_to: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
+}
+
+impl InferenceProvider {
+    /// Execute the provider, loading [`HostData`] from the host which includes the provider's configuration and
+    /// information about the host. Once you use the passed configuration to construct a [`InferenceProvider`],
+    /// you can run the provider by calling `run_provider` and then serving the provider's exports on the proper
+    /// RPC topics via `wrpc::serve`.
+    ///
+    /// This step is essentially the same for every provider, and you shouldn't need to modify this function.
+    pub async fn run() -> anyhow::Result<()> {
+        let provider = Self::default();
+        let shutdown = run_provider(provider.clone(), "custom-template-provider")
+            .await
+            .context("failed to run provider")?;
+
+        // The [`serve`] function will set up RPC topics for your provider's exports and await invocations.
+        // This is a generated function based on the contents in your `wit/world.wit` file.
+        let connection = wasmcloud_provider_sdk::get_connection();
+
+        serve_provider_exports(
+            &connection.get_wrpc_client(connection.provider_key()),
+            provider,
+            shutdown,
+            crate::serve,
+        )
+        .await
+        .context("failed to serve provider exports")
+
+        // serve(
+        //     &connection.get_wrpc_client(connection.provider_key()),
+        //     provider,
+        //     shutdown,
+        // )
+        // .await
+
+        // If your provider has no exports, simply await the shutdown to keep the provider running
+        // shutdown.await;
+        // Ok(())
+    }
+
+    pub async fn get_registry(&self) -> String {
+        let config_guard = self.config.read().await;
+
+        let registry = match config_guard.setting(CONFIG_URL_KEY) {
+            Some(url) => url.to_string(),
+            None => DEFAULT_CONNECT_URL.to_string(),
+        };
+
+        registry
+    }
+
+    /// Starts loading the model in the background and returns right away.
+    /// Nothing is started in case the model is loading already. A model which
+    /// is already ready remains available while it is reloaded.
+    pub async fn prefetch_model(&self, model_id: &str) {
+        {
+            let mut models_lock = self.models.write().await;
+
+            match models_lock.get_mut(model_id) {
+                Some(entry) if entry.state.is_loading() => {
+                    debug!("model '{}' is already {}", model_id, entry.state);
+                    return;
+                }
+                Some(entry) => {
+                    if entry.context.is_none() {
+                        entry.state = LoadState::Pending;
+                    }
+                }
+                None => {
+                    models_lock.insert(model_id.to_owned(), ModelEntry::pending());
+                }
+            }
+        }
+
+        let provider = self.clone();
+        let model_id = model_id.to_owned();
+
+        tokio::spawn(async move {
+            if let Err(error) = provider.load_model(&model_id).await {
+                log::error!("prefetch_model() - failed to load model '{}': {}", model_id, error);
+                provider
+                    .set_state(&model_id, LoadState::Failed(error.to_string()))
+                    .await;
+            }
+        });
+    }
+
+    /// Fetches the model from the registry, or from the compile cache if possible,
+    /// and registers it, while advancing its load state accordingly.
+    async fn load_model(&self, model_id: &str) -> anyhow::Result<()> {
+        self.set_state(model_id, LoadState::Downloading).await;
+
+        let registry = self.get_registry().await;
+        let image = format!("{}/{}", registry, model_id);
+        let compile_cache = self.config.read().await.compile_cache();
+
+        // the manifest's digest tells whether a cached compilation is still current
+        let manifest_digest = match compile_cache {
+            Some(_) => self.manifest_digest(&image).await,
+            None => None,
+        };
+
+        let cached = match compile_cache {
+            Some(ref cache) => {
+                self.load_cached(cache, &image, manifest_digest.as_deref(), model_id)
+                    .await
+            }
+            None => None,
+        };
+
+        let model_data = match cached {
+            Some(model_data) => model_data,
+            None => data_loader::fetch_model(&registry, model_id)
+                .await
+                .map_err(|error| anyhow!(error.to_string()))?,
+        };
+
+        let digest = model_data.digest.clone();
+        let metadata = model_data.metadata.clone();
+
+        self.set_state(model_id, LoadState::Compiling).await;
+
+        self.register_model(model_id, model_data).await?;
+
+        if let Some(ref cache) = compile_cache {
+            let manifest_digest = manifest_digest.as_deref();
+            self.cache_compiled(cache, &image, manifest_digest, model_id, &digest, &metadata)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Digest of the image's manifest as currently tagged in the registry,
+    /// `None` in case the registry cannot tell, e.g. since it is unreachable.
+    async fn manifest_digest(&self, image: &str) -> Option<String> {
+        match data_loader::fetch_manifest_digest(image).await {
+            Ok(manifest_digest) => Some(manifest_digest),
+            Err(error) => {
+                log::warn!(
+                    "manifest_digest() - cannot verify cached compilation of '{}': {}",
+                    image,
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    /// Loads the model from the compile cache, `None` in case it is not cached or
+    /// outdated, i.e. the image's current `manifest_digest` differs. Without the
+    /// current digest, the compilation cached for the `image` is loaded regardless,
+    /// such that a cached model loads while the registry is offline.
+    async fn load_cached(
+        &self,
+        cache: &CompileCache,
+        image: &str,
+        manifest_digest: Option<&str>,
+        model_id: &str,
+    ) -> Option<ModelRawData> {
+        let key = cache.lookup(image, manifest_digest).await?;
+        let model_data = cache.load(&key).await?;
+        info!("loading model '{}' from the compile cache", model_id);
+
+        Some(model_data)
+    }
+
+    /// Stores the model as compiled by its engine in the compile cache. Models
+    /// loaded from the cache, and models the engine cannot serialize, are skipped.
+    async fn cache_compiled(
+        &self,
+        cache: &CompileCache,
+        image: &str,
+        manifest_digest: Option<&str>,
+        model_id: &str,
+        digest: &str,
+        metadata: &ModelMetadata,
+    ) {
+        // batches differ in shape from the declared input, unless the
+        // model is compiled for any batch size along its batch axis
+        if metadata.batch_axis.is_none()
+            && self.config.read().await.batch_config(model_id).is_some()
+        {
+            debug!("model '{}' is batched, it is not compiled for the cache", model_id);
+            return;
+        }
+
+        let model_context = match self.ready_context(model_id).await {
+            Ok(model_context) if compile_cache::is_cacheable(&model_context.graph_encoding) => {
+                model_context
+            }
+            _ => return,
+        };
+
+        let engine =
+            match get_engine(Arc::clone(&self.engines), &model_context.graph_encoding).await {
+                Ok(engine) => engine,
+                Err(_) => return,
+            };
+
+        let input = model_context
+            .tensor_shape_in
+            .map(|shape| (model_context.dtype, shape));
+
+        match engine
+            .compile_to_nnef(model_context.graph_execution_context, input)
+            .await
+        {
+            Ok(Some(compiled)) => {
+                cache
+                    .store(image, manifest_digest, digest, metadata, &compiled)
+                    .await
+            }
+            Ok(None) => debug!("engine of model '{}' cannot compile it for the cache", model_id),
+            Err(error) => log::warn!(
+                "cache_compiled() - failed to compile model '{}' for the cache: {}",
+                model_id,
+                error
+            ),
+        }
+    }
+
+    /// Updates the load state of a model which is not ready (yet).
+    /// Nothing happens in case the model has been preempted meanwhile.
+    async fn set_state(&self, model_id: &str, state: LoadState) {
+        if let Some(entry) = self.models.write().await.get_mut(model_id) {
+            if entry.context.is_none() {
+                entry.state = state;
+            }
+        }
+
+        self.state_changed.notify_waiters();
+    }
+
+    /// Points an alias, whose model has been evicted, at its model once more, such
+    /// that the model is loaded again. The request waits until the model is swapped
+    /// in. Nothing happens for model ids which are no alias.
+    async fn restore_alias(&self, alias: &str) -> Result<(), MlError> {
+        let model_id = match self.aliases.read().await.get(alias) {
+            Some(model_id) => model_id.clone(),
+            None => return Ok(()),
+        };
+
+        if !self.models.read().await.contains_key(alias) {
+            info!("loading model '{}' of alias '{}' again", model_id, alias);
+            self.point_alias(alias, &model_id).await?;
+        }
+
+        self.wait_until_loaded(alias).await
+    }
+
+    /// Makes sure the model is loaded, in case autoload is configured:
+    /// an unknown or failed model is fetched, and the request waits until the
+    /// model is loaded. Concurrent requests share the very same download.
+    /// A model which failed to load is only retried once its backoff passed,
+    /// and an unknown model is not kept in the zoo in case it fails to load.
+    async fn autoload(&self, model_id: &str) -> Result<(), MlError> {
+        if !self.config.read().await.autoload() {
+            return Ok(());
+        }
+
+        let state = self
+            .models
+            .read()
+            .await
+            .get(model_id)
+            .map(|entry| entry.state.clone());
+
+        if matches!(state, None | Some(LoadState::Failed(_))) {
+            if let Some(failure) = self.autoload_failures.read().await.get(model_id) {
+                if let Some(retry_after) = failure.retry_after() {
+                    return Err(MlError::NotReady(format!(
+                        "Model '{}' failed to load: {}, retry after {:?}",
+                        model_id, failure.reason, retry_after
+                    )));
+                }
+            }
+
+            info!("autoloading model '{}'", model_id);
+            self.prefetch_model(model_id).await;
+        }
+
+        let waited = self.wait_until_loaded(model_id).await;
+
+        let reason = match self.model_state(model_id).await {
+            Ok(LoadState::Failed(reason)) => {
+                self.autoload_failed(model_id, &reason, state.is_none()).await;
+                reason
+            }
+            Ok(_) => {
+                self.autoload_failures.write().await.remove(model_id);
+                return waited;
+            }
+            // removed meanwhile by a concurrent request, whose load failed
+            Err(error) => match self.autoload_failures.read().await.get(model_id) {
+                Some(failure) => failure.reason.clone(),
+                None => return Err(error),
+            },
+        };
+
+        Err(MlError::NotReady(format!(
+            "Model '{}' failed to load: {}",
+            model_id, reason
+        )))
+    }
+
+    /// Records the failed autoload of a model, which backs off the next attempt.
+    /// An `unknown` model, i.e. one which was not registered before, is removed.
+    async fn autoload_failed(&self, model_id: &str, reason: &str, unknown: bool) {
+        let now = Instant::now();
+
+        {
+            let mut failures_lock = self.autoload_failures.write().await;
+
+            // failures are forgotten some time after their backoff passed
+            failures_lock.retain(|_, failure| failure.retry_at + AUTOLOAD_MAX_BACKOFF > now);
+
+            // concurrent requests waiting for the same load only count once
+            let failure = failures_lock
+                .entry(model_id.to_owned())
+                .or_insert_with(|| AutoloadFailure::new(reason));
+            if failure.retry_at <= now {
+                failure.failed(reason, now);
+            }
+        }
+
+        if unknown {
+            let mut models_lock = self.models.write().await;
+            if models_lock
+                .get(model_id)
+                .is_some_and(|entry| matches!(entry.state, LoadState::Failed(_)))
+            {
+                models_lock.remove(model_id);
+            }
+        }
+    }
+
+    /// Waits as long as the model is loading.
+    async fn wait_until_loaded(&self, model_id: &str) -> Result<(), MlError> {
+        loop {
+            // the notification is enabled before the state is checked,
+            // such that no change in between goes unnoticed
+            let notified = self.state_changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if !self.model_state(model_id).await?.is_loading() {
+                return Ok(());
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Points `alias` at the model `model_id`, which is loaded in the background
+    /// if necessary. The alias keeps serving requests by its previous model until
+    /// the new one is ready and swapped in, see `swap_alias()`.
+    pub async fn point_alias(&self, alias: &str, model_id: &str) -> Result<(), MlError> {
+        if alias == model_id {
+            return Err(MlError::Internal(format!(
+                "Alias '{}' must not point at itself",
+                alias
+            )));
+        }
+
+        self.aliases
+            .write()
+            .await
+            .insert(alias.to_owned(), model_id.to_owned());
+
+        self.models
+            .write()
+            .await
+            .entry(alias.to_owned())
+            .or_insert_with(ModelEntry::pending);
+
+        if !self.models.read().await.contains_key(model_id) {
+            self.prefetch_model(model_id).await;
+        }
+
+        let provider = self.clone();
+        let alias = alias.to_owned();
+        let model_id = model_id.to_owned();
+
+        tokio::spawn(async move {
+            if let Err(error) = provider.wait_until_loaded(&model_id).await {
+                log::error!("point_alias() - model '{}' did not load: {:?}", model_id, error);
+            }
+            provider.swap_alias(&alias, &model_id).await;
+        });
+
+        Ok(())
+    }
+
+    /// Swaps the model `model_id` in as the alias' model, unless the alias has
+    /// been pointed elsewhere meanwhile. The alias' entry is replaced at once,
+    /// requests in flight complete on the previous model.
+    async fn swap_alias(&self, alias: &str, model_id: &str) {
+        if self.aliases.read().await.get(alias).map(String::as_str) != Some(model_id) {
+            debug!("alias '{}' no longer points at '{}'", alias, model_id);
+            return;
+        }
+
+        let model_context = match self.ready_context(model_id).await {
+            Ok(model_context) => model_context,
+            Err(error) => {
+                log::error!(
+                    "swap_alias() - alias '{}' keeps its previous model, '{}' is not available: {:?}",
+                    alias,
+                    model_id,
+                    error
+                );
+                self.set_state(alias, LoadState::Failed(format!("{:?}", error)))
+                    .await;
+                return;
+            }
+        };
+
+        // the alias holds a reference to the graph on its own, such that
+        // the graph outlives the model in case that is preempted
+        if !self.reference_graph(&model_context.digest).await {
+            log::error!("swap_alias() - graph of '{}' is gone", model_id);
+            return;
+        }
+
+        let previous = self
+            .models
+            .write()
+            .await
+            .insert(alias.to_owned(), ModelEntry::ready(model_context));
+
+        info!("alias '{}' now points at '{}'", alias, model_id);
+
+        if let Some(previous_context) = previous.and_then(|entry| entry.context) {
+            self.release_graph(&previous_context).await;
+        }
+
+        self.state_changed.notify_waiters();
+    }
+
+    /// Load state of the model registered as `model_id`
+    pub async fn model_state(&self, model_id: &str) -> Result<LoadState, MlError> {
+        match self.models.read().await.get(model_id) {
+            Some(entry) => Ok(entry.state.clone()),
+            None => Err(MlError::ContextNotFoundError(format!(
+                "No model registered under '{}'",
+                model_id
+            ))),
+        }
+    }
+
+    pub async fn register_model(
+        &self,
+        model_id: &str,
+        model_data: ModelRawData,
+    ) -> anyhow::Result<()> {
+        let metadata = model_data.metadata;
+
+        let graph_encoding = GraphEncoding::from_str(&metadata.graph_encoding)
+            .map_err(|error| anyhow!(error.to_string()))?;
+
+        let execution_target = ExecutionTarget::from_str(&metadata.execution_target)
+            .map_err(|error| anyhow!(error.to_string()))?;
+
+        let data_type = DataType::from_str(&metadata.tensor_dtype)
+            .map_err(|error| anyhow!(error.to_string()))?;
+
+        let (graph, gec) = self
+            .acquire_graph(
+                &model_data.digest,
+                &model_data.model,
+                &graph_encoding,
+                &execution_target,
+            )
+            .await?;
+
+        let mut model_context = ModelContext {
+            model_name: model_id.to_owned(),
+            digest: model_data.digest,
+            graph_encoding,
+            execution_target,
+            dtype: data_type,
+            tensor_shape_in: metadata.tensor_shape_in,
+            tensor_shape_out: metadata.tensor_shape_out,
+            batch_axis: metadata.batch_axis,
+            inputs: Vec::new(),
+            warmup_latency: None,
+            graph,
+            graph_execution_context: gec,
+        };
+
+        if let Some(axis) = model_context.batch_axis {
+            let engine = get_engine(Arc::clone(&self.engines), &graph_encoding).await?;
+
+            if let Err(error) = engine.set_batch_axis(gec, axis).await {
+                log::warn!(
+                    "register_model() - model '{}' ignores its batch axis: {}",
+                    model_id,
+                    error
+                );
+
+                // requests are then validated against the fixed batch size
+                model_context.batch_axis = None;
+            }
+        }
+
+        model_context.inputs = self.declared_inputs(&model_context).await;
+
+        // resolved once, the limit applies to the warm-up already
+        let max_concurrency = self.config.read().await.max_concurrency(model_id);
+        self.workers().await.set_limit(model_id, max_concurrency);
+
+        // the model only becomes ready once warmed up, such
+        // that its first request is served at full speed
+        model_context.warmup_latency = self.warm_up(&model_context).await;
+
+        let mut models_lock = self.models.write().await;
+
+        // the entry is gone in case the model was preempted while loading
+        if !models_lock.contains_key(model_id) {
+            drop(models_lock);
+            self.release_graph(&model_context).await;
+
+            return Err(anyhow!("model '{}' was preempted while loading", model_id));
+        }
+
+        let already_entry =
+            models_lock.insert(model_id.to_owned(), ModelEntry::ready(model_context));
+        drop(models_lock);
+
+        if let Some(already_context) = already_entry.and_then(|entry| entry.context) {
+            log::warn!(
+                "model '{}' is already registered: {:?}",
+                model_id,
+                already_context
+            );
+            self.release_graph(&already_context).await;
+        }
+
+        self.state_changed.notify_waiters();
+
+        Ok(())
+    }
+
+    /// The model's inputs as declared by its graph, with the first input
+    /// complemented by the metadata and the batch axis, if any, left open.
+    /// Empty in case the engine cannot tell.
+    async fn declared_inputs(&self, model_context: &ModelContext) -> Vec<TensorInfo> {
+        let engine =
+            match get_engine(Arc::clone(&self.engines), &model_context.graph_encoding).await {
+                Ok(engine) => engine,
+                Err(_) => return Vec::new(),
+            };
+
+        let mut inputs = match engine.input_infos(model_context.graph_execution_context).await {
+            Ok(inputs) => inputs,
+            Err(error) => {
+                log::warn!(
+                    "declared_inputs() - inputs of model '{}' are unknown: {}",
+                    model_context.model_name,
+                    error
+                );
+                return Vec::new();
+            }
+        };
+
+        if let Some(input) = inputs.first_mut() {
+            complement_tensor_info(
+                input,
+                &model_context.tensor_shape_in,
+                Some(model_context.dtype),
+            );
+        }
+
+        // any batch size is accepted along the batch axis
+        if let Some(axis) = model_context.batch_axis {
+            for input in inputs.iter_mut() {
+                if let Some(dim) = input.shape.as_mut().and_then(|shape| shape.get_mut(axis)) {
+                    *dim = DimensionInfo::Symbolic("N".to_string());
+                }
+            }
+        }
+
+        inputs
+    }
+
+    /// Runs an inference with tensors of zeros, such that the engine optimizes the
+    /// model and allocates its buffers ahead of the first request. The first input
+    /// is shaped as declared by the metadata, since that is what requests carry,
+    /// others as declared by the model. Returns the inference's latency, `None`
+    /// in case the inputs' shapes are unknown or the inference failed.
+    async fn warm_up(&self, model_context: &ModelContext) -> Option<Duration> {
+        let model_id = &model_context.model_name;
+        let engine = get_engine(Arc::clone(&self.engines), &model_context.graph_encoding)
+            .await
+            .ok()?;
+        let gec = model_context.graph_execution_context;
+        let graph_handle = self.graph_handle(model_context).await.ok()?;
+
+        let mut infos = model_context.inputs.clone();
+
+        if let (Some(input), Some(shape)) = (infos.first_mut(), &model_context.tensor_shape_in) {
+            input.shape = Some(shape.iter().map(|&d| DimensionInfo::Fixed(d)).collect());
+        }
+
+        let mut inputs = Vec::with_capacity(infos.len());
+        for (index, info) in infos.iter().enumerate() {
+            match zero_tensor(info) {
+                Some(tensor) => inputs.push((index as u32, tensor)),
+                None => {
+                    debug!(
+                        "model '{}' is not warmed up, input '{}' is not fully declared",
+                        model_id, info.name
+                    );
+                    return None;
+                }
+            }
+        }
+
+        let started = Instant::now();
+
+        let warmed_up = self
+            .workers()
+            .await
+            .run(model_id, Vec::new(), None, async move {
+                let _graph_handle = graph_handle;
+                engine.infer(gec, inputs).await
+            })
+            .await;
+
+        match warmed_up.and_then(|result| result.map_err(MlError::from)) {
+            Ok(_) => {
+                let latency = started.elapsed();
+                info!("warmed up model '{}' in {:?}", model_id, latency);
+                Some(latency)
+            }
+            Err(error) => {
+                log::warn!("warm_up() - warm-up of model '{}' failed: {:?}", model_id, error);
+                None
+            }
+        }
+    }
+
+    /// Returns the graph and execution context of the model with given digest.
+    /// The model is only loaded into the engine in case no other model id refers
+    /// to the very same content yet, otherwise the loaded graph is shared.
+    async fn acquire_graph(
+        &self,
+        digest: &str,
+        model: &[u8],
+        graph_encoding: &GraphEncoding,
+        execution_target: &ExecutionTarget,
+    ) -> anyhow::Result<(Graph, GraphExecutionContext)> {
+        if let Some(loaded) = self.graphs.write().await.get_mut(digest) {
+            loaded.references += 1;
+            debug!(
+                "sharing graph of '{}' which is now referenced {} times",
+                digest, loaded.references
+            );
+            return Ok((loaded.handle.graph, loaded.handle.graph_execution_context));
+        }
+
+        let reservation = self.make_room(model.len()).await?;
+
+        let engine = get_or_else_set_engine(Arc::clone(&self.engines), graph_encoding).await?;
+
+        let graph: Graph = engine
+            .load(model)
+            .await
+            .map_err(|error| anyhow!(error.to_string()))?;
+
+        let gec: GraphExecutionContext = engine
+            .init_execution_context(graph, execution_target, graph_encoding)
+            .await
+            .map_err(|error| anyhow!(error.to_string()))?;
+
+        let mut graphs_lock = self.graphs.write().await;
+
+        // the loaded graph, if any, takes over the reserved room
+        drop(reservation);
+
+        // another registration of the same content may have won the race
+        if let Some(loaded) = graphs_lock.get_mut(digest) {
+            loaded.references += 1;
+            let shared = (loaded.handle.graph, loaded.handle.graph_execution_context);
+            drop(graphs_lock);
+
+            engine.drop_model_state(&graph, &gec).await;
+            return Ok(shared);
+        }
+
+        graphs_lock.insert(
+            digest.to_owned(),
+            LoadedGraph {
+                handle: Arc::new(GraphHandle::new(engine, graph, gec)),
+                size: model.len(),
+                references: 1,
+            },
+        );
+
+        Ok((graph, gec))
+    }
+
+    /// Unloads the least recently used models until another graph of `size` bytes
+    /// fits into the configured limits and reserves the room for it, such that
+    /// concurrent loads do not exceed the limits together. Pinned models are never
+    /// unloaded, so the graph is rejected in case the limits can't be met otherwise.
+    async fn make_room(&self, size: usize) -> anyhow::Result<Reservation> {
+        let limits = self.config.read().await.model_limits();
+
+        loop {
+            let (loaded, count, used) = {
+                let graphs_lock = self.graphs.read().await;
+                let mut reserved = self.reserved.lock().unwrap_or_else(|e| e.into_inner());
+
+                let count = graphs_lock.len() + reserved.graphs;
+                let used = graphs_lock.values().map(|loaded| loaded.size).sum::<usize>()
+                    + reserved.bytes;
+
+                if !limits.exceeded_by(count + 1, used + size) {
+                    reserved.graphs += 1;
+                    reserved.bytes += size;
+
+                    return Ok(Reservation {
+                        reserved: Arc::clone(&self.reserved),
+                        size,
+                    });
+                }
+
+                (graphs_lock.len(), count, used)
+            };
+
+            let (model_ids, aliases) = match self.eviction_candidate().await {
+                Some(candidate) => candidate,
+                None => {
+                    return Err(anyhow!(
+                        "model of {} bytes exceeds the limits {:?}, with {} models of {} bytes \
+                        loaded, none of which can be evicted",
+                        size,
+                        limits,
+                        count,
+                        used
+                    ))
+                }
+            };
+
+            let mut reclaimed = 0;
+            for model_id in model_ids.iter() {
+                reclaimed += self.unload_model(model_id).await.unwrap_or_default();
+            }
+
+            // the aliases keep pointing at their model, which is
+            // loaded again on their next request, see `restore_alias()`
+            for alias in aliases.iter() {
+                reclaimed += self.remove_model(alias).await.unwrap_or_default();
+            }
+
+            info!("evicted {:?} and reclaimed {} bytes", model_ids, reclaimed);
+
+            if self.graphs.read().await.len() >= loaded {
+                return Err(anyhow!("failed to evict {:?}", model_ids));
+            }
+        }
+    }
+
+    /// Ids of the models and of the aliases which share the least recently used
+    /// graph, unless any of them is pinned. Aliases are listed on their own, since
+    /// only their model is unloaded, not the aliases themselves.
+    async fn eviction_candidate(&self) -> Option<(Vec<ModelId>, Vec<String>)> {
+        let config_lock = self.config.read().await;
+        let models_lock = self.models.read().await;
+        let aliases_lock = self.aliases.read().await;
+
+        let mut graphs: HashMap<&str, EvictionCandidate> = HashMap::new();
+
+        for (model_id, entry) in models_lock.iter() {
+            if let Some(ref model_context) = entry.context {
+                let graph = graphs.entry(model_context.digest.as_str()).or_default();
+
+                graph.last_active = graph.last_active.max(entry.last_active());
+                graph.pinned |= config_lock.is_pinned(model_id);
+
+                match aliases_lock.contains_key(model_id) {
+                    true => graph.aliases.push(model_id.to_owned()),
+                    false => graph.model_ids.push(model_id.to_owned()),
+                }
+            }
+        }
+
+        graphs
+            .into_values()
+            .filter(|graph| !graph.pinned)
+            .min_by_key(|graph| graph.last_active)
+            .map(|graph| (graph.model_ids, graph.aliases))
+    }
+
+    /// Adds a reference to a loaded graph. Returns false if there is no such graph.
+    async fn reference_graph(&self, digest: &str) -> bool {
+        match self.graphs.write().await.get_mut(digest) {
+            Some(loaded) => {
+                loaded.references += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handle of the model context's graph, which keeps the engine's state alive
+    /// while an inference is queued or running. Fails with `NotReady` in case the
+    /// graph has been dropped, or replaced by another load of the same content.
+    async fn graph_handle(&self, model_context: &ModelContext) -> Result<Arc<GraphHandle>, MlError> {
+        match self.graphs.read().await.get(&model_context.digest) {
+            Some(loaded)
+                if loaded.handle.graph_execution_context
+                    == model_context.graph_execution_context =>
+            {
+                Ok(Arc::clone(&loaded.handle))
+            }
+            _ => Err(MlError::NotReady(format!(
+                "Model '{}' has been unloaded",
+                model_context.model_name
+            ))),
+        }
+    }
+
+    /// Releases the model context's reference to its graph. The engine's state
+    /// is dropped along with the last reference, as soon as the inferences still
+    /// in flight on the graph have finished, see `GraphHandle`. Returns the number
+    /// of bytes reclaimed, which is 0 as long as other model ids still share the graph.
+    async fn release_graph(&self, model_context: &ModelContext) -> usize {
+        let mut graphs_lock = self.graphs.write().await;
+
+        let loaded = match graphs_lock.get_mut(&model_context.digest) {
+            Some(loaded) => loaded,
+            None => {
+                log::warn!(
+                    "release_graph() - no graph loaded for digest '{}'",
+                    model_context.digest
+                );
+                return 0;
+            }
+        };
+
+        loaded.references -= 1;
+
+        if loaded.references > 0 {
+            debug!(
+                "graph of '{}' is still referenced {} times",
+                model_context.digest, loaded.references
+            );
+            return 0;
+        }
+
+        let loaded = graphs_lock.remove(&model_context.digest).unwrap();
+        drop(graphs_lock);
+
+        let in_flight = Arc::strong_count(&loaded.handle) - 1;
+        if in_flight > 0 {
+            debug!(
+                "graph of '{}' is dropped once {} inference(s) in flight are done",
+                model_context.digest, in_flight
+            );
+        }
+
+        info!(
+            "dropped graph of '{}', reclaimed {} bytes",
+            model_context.digest, loaded.size
+        );
+
+        loaded.size
+    }
+
+    /// Removes the model registered as `model_id`, which may as well be an alias.
+    /// Returns the number of bytes reclaimed, see `release_graph()`.
+    pub async fn unload_model(&self, model_id: &str) -> Result<usize, MlError> {
+        self.aliases.write().await.remove(model_id);
+        self.remove_model(model_id).await
+    }
+
+    /// Removes the entry registered as `model_id` and releases its graph. Unlike
+    /// `unload_model()`, an alias is kept and loads its model again on demand.
+    async fn remove_model(&self, model_id: &str) -> Result<usize, MlError> {
+        let entry = match self.models.write().await.remove(model_id) {
+            Some(entry) => entry,
+            None => {
+                return Err(MlError::ContextNotFoundError(format!(
+                    "No model registered under '{}'",
+                    model_id
+                )))
+            }
+        };
+
+        self.state_changed.notify_waiters();
+
+        // the batcher stops once the requests queued so far are computed, a model
+        // loaded again under the same id gets a batcher with its current settings
+        if self.batchers.write().await.remove(model_id).is_some() {
+            debug!("stopping batcher of model '{}'", model_id);
+        }
+
+        match entry.context {
+            Some(ref model_context) => Ok(self.release_graph(model_context).await),
+            None => Ok(0),
+        }
+    }
+
+    /// Context of the model registered as `model_id`, as long as it is ready.
+    async fn ready_context(&self, model_id: &str) -> Result<ModelContext, MlError> {
+        let models_lock = self.models.read().await;
+
+        match models_lock.get(model_id) {
+            Some(entry) => match entry.ready_context() {
+                Some(model_context) => Ok(model_context.clone()),
+                None => Err(MlError::NotReady(format!(
+                    "Model '{}' is not ready, it is {}",
+                    model_id, entry.state
+                ))),
+            },
+            None => {
+                log::error!(
+                    "ready_context() - model '{}' not found in models {:?}",
+                    model_id,
+                    models_lock.keys()
+                );
+                Err(MlError::ContextNotFoundError(format!(
+                    "Model '{}' is unknown",
+                    model_id
+                )))
+            }
+        }
+    }
+
+    /// Overview of all registered models, ordered by their id
+    pub async fn model_summaries(&self) -> Vec<ModelSummary> {
+        let models_lock = self.models.read().await;
+
+        let mut summaries: Vec<ModelSummary> = models_lock
+            .iter()
+            .map(|(model_id, entry)| ModelSummary {
+                model_id: model_id.to_owned(),
+                encoding: entry.context.as_ref().map(|mc| mc.graph_encoding.into()),
+                execution_target: entry.context.as_ref().map(|mc| (&mc.execution_target).into()),
+                state: (&entry.state).into(),
+                loaded_at: entry.loaded_at.map(unix_millis),
+                last_used: entry.last_used(),
+            })
+            .collect();
+
+        summaries.sort_by(|a, b| a.model_id.cmp(&b.model_id));
+        summaries
+    }
+
+    /// Describes a registered model. Whatever the engine cannot determine
+    /// about the first input and output is taken from the model's metadata.
+    pub async fn model_info(&self, model_id: &str) -> Result<ModelInfo, MlError> {
+        let model_context = self.ready_context(model_id).await?;
+
+        let engine = get_engine(Arc::clone(&self.engines), &model_context.graph_encoding).await?;
+        let gec = model_context.graph_execution_context;
+
+        let inputs = model_context.inputs.clone();
+        let mut outputs = engine.output_infos(gec).await?;
+
+        if let Some(output) = outputs.first_mut() {
+            complement_tensor_info(output, &model_context.tensor_shape_out, None);
+        }
+
+        Ok(ModelInfo {
+            model_id: model_id.to_owned(),
+            encoding: model_context.graph_encoding.into(),
+            execution_target: (&model_context.execution_target).into(),
+            inputs,
+            outputs,
+            warmup_latency_ms: model_context
+                .warmup_latency
+                .map(|latency| latency.as_secs_f64() * 1000.0),
+        })
+    }
+
+    /// Spreads the requests for `name` across the given models by their weight.
+    /// An empty list of targets removes the route.
+    pub async fn update_route(&self, name: &str, targets: Vec<RouteTarget>) -> Result<(), MlError> {
+        let mut routes_lock = self.routes.write().await;
+
+        if targets.is_empty() {
+            routes_lock.remove(name);
+            return Ok(());
+        }
+
+        let route = Route::new(name, targets, routes_lock.get(name))?;
+        routes_lock.insert(name.to_owned(), route);
+
+        Ok(())
+    }
+
+    pub async fn route_version_stats(&self, name: &str) -> Result<Vec<RouteVersionStats>, MlError> {
+        match self.routes.read().await.get(name) {
+            Some(route) => Ok(route.stats()),
+            None => Err(MlError::ContextNotFoundError(format!(
+                "No route registered under '{}'",
+                name
+            ))),
+        }
+    }
+
+    /// Entry point of `predict` and `predict-many`. In case a route is registered
+    /// as `model_id`, the request is served by one of the route's models, which is
+    /// selected by weight, or sticky in case the request comes with a `routing_key`.
+    /// The request fails with a timeout once the caller's `deadline` passes.
+    pub async fn predict_tensors(
+        &self,
+        model_id: &str,
+        routing_key: Option<&str>,
+        deadline: Option<Instant>,
+        tensors_in: Vec<NamedTensor>,
+    ) -> Result<Vec<NamedTensor>, MlError> {
+        let routed = self
+            .routes
+            .read()
+            .await
+            .get(model_id)
+            .map(|route| route.select(routing_key));
+
+        let shadow_model_id = self
+            .config
+            .read()
+            .await
+            .shadow_model(model_id)
+            .map(str::to_owned);
+        let shadow_tensors_in = shadow_model_id.as_ref().map(|_| tensors_in.clone());
+
+        let version = routed.as_deref().unwrap_or(model_id);
+        let started = Instant::now();
+        let result = self.predict_model(version, deadline, tensors_in).await;
+        let primary_latency = started.elapsed();
+
+        if let (Some(shadow_model_id), Some(tensors_in), Ok(tensors_out)) =
+            (shadow_model_id, shadow_tensors_in, result.as_ref())
+        {
+            let provider = self.clone();
+            let model_id = model_id.to_owned();
+            let tensors_out = tensors_out.clone();
+
+            tokio::spawn(async move {
+                provider
+                    .shadow(
+                        &model_id,
+                        &shadow_model_id,
+                        tensors_in,
+                        tensors_out,
+                        primary_latency,
+                    )
+                    .await
+            });
+        }
+
+        if let Some(ref version) = routed {
+            debug!("route '{}' served by '{}'", model_id, version);
+
+            if let Some(route) = self.routes.read().await.get(model_id) {
+                route.record(version, result.is_ok());
+            }
+        }
+
+        result
+    }
+
+    /// Runs the request on the shadow model and records how far its outputs
+    /// diverge from those of the production model. Skipped shadow requests
+    /// are not recorded at all, see `predict_shadow()`.
+    async fn shadow(
+        &self,
+        model_id: &str,
+        shadow_model_id: &str,
+        tensors_in: Vec<NamedTensor>,
+        primary_tensors_out: Vec<NamedTensor>,
+        primary_latency: Duration,
+    ) {
+        let started = Instant::now();
+        let result = match self.predict_shadow(shadow_model_id, tensors_in).await {
+            Some(result) => result,
+            None => return,
+        };
+        let shadow_latency = started.elapsed();
+
+        let mut shadows_lock = self.shadows.write().await;
+
+        // the comparison starts over whenever another shadow model is configured
+        let stats = shadows_lock
+            .entry(model_id.to_owned())
+            .or_insert_with(|| ShadowStats::new(shadow_model_id));
+        if stats.shadow_model_id() != shadow_model_id {
+            *stats = ShadowStats::new(shadow_model_id);
+        }
+
+        match result {
+            Ok(shadow_tensors_out) => {
+                let comparison = shadowing::compare(&primary_tensors_out, &shadow_tensors_out);
+                debug!(
+                    "shadow model '{}' of '{}' diverges by {:?}",
+                    shadow_model_id, model_id, comparison
+                );
+                stats.record(comparison, primary_latency, shadow_latency);
+            }
+            Err(error) => {
+                log::warn!(
+                    "shadow model '{}' of '{}' failed: {:?}",
+                    shadow_model_id,
+                    model_id,
+                    error
+                );
+                stats.record_failure();
+            }
+        }
+    }
+
+    /// Runs the request on the shadow model on a best-effort basis. Shadow requests
+    /// neither load the shadow model nor take a place in its queue, and they are
+    /// skipped with `None` while the shadow model is not ready or the workers are
+    /// busy, such that they never delay regular requests.
+    async fn predict_shadow(
+        &self,
+        shadow_model_id: &str,
+        tensors_in: Vec<NamedTensor>,
+    ) -> Option<Result<Vec<NamedTensor>, MlError>> {
+        let model_context = self
+            .models
+            .read()
+            .await
+            .get(shadow_model_id)
+            .and_then(|entry| entry.ready_context().cloned());
+        let model_context = match model_context {
+            Some(model_context) => model_context,
+            None => {
+                debug!("shadow model '{}' is not ready, skipping", shadow_model_id);
+                return None;
+            }
+        };
+
+        if !self.workers().await.has_capacity(shadow_model_id) {
+            debug!("workers are busy, skipping shadow model '{}'", shadow_model_id);
+            return None;
+        }
+
+        if let Err(error) = validation::validate_inputs(&tensors_in, &model_context.inputs) {
+            return Some(Err(error));
+        }
 
-#[derive(Default, Clone)]
-pub struct InferenceProvider {
-    /// map to store the assignments between the respective model
-    /// and corresponding bindle path for each linked actor
-    /// TODO:
-    ///   - instead of delaying putLink for model loading and initialization,
-    ///     add a Ready flag (AtomicBool) that is set after model is loaded and initialized.
-    ///   - initialize actor link as soon as we receive the putlink command
-    ///   - if health check or rpc is received when not ready, return not-ready error
-    // components: Arc<RwLock<HashMap<String, ModelZoo>>>,
-    models: Arc<RwLock<ModelZoo>>,
+        let timeout = self.config.read().await.timeout(shadow_model_id);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
 
-    config: Arc<RwLock<ProviderConfig>>,
+        Some(self.infer(shadow_model_id, tensors_in, Vec::new(), deadline).await)
+    }
 
-    /// There are the following relevant types:
-    ///     - InferenceFramework
-    ///     - Engine
-    ///     - InferenceEngine
-    ///     - GraphEncoding
-    ///
-    /// InferenceFramework corresponds to an integrated crate.
-    /// An InferenceFramework may support a multitude of Engine
-    /// An InferenceFramework may support a multitude of GraphEncoding.
-    /// Engine is a wrapper of InferenceEngine
-    /// InferenceEngine defines common behavior
-    /// GraphEncoding defines a model's encoding.
-    engines: Arc<RwLock<HashMap<InferenceFramework, Engine>>>,
+    pub async fn shadow_comparison(&self, model_id: &str) -> Result<ShadowReport, MlError> {
+        match self.shadows.read().await.get(model_id) {
+            Some(stats) => Ok(stats.report()),
+            None => Err(MlError::ContextNotFoundError(format!(
+                "No shadow model has been compared with '{}' yet",
+                model_id
+            ))),
+        }
+    }
 
-    /// All components linked to this provider and their config.
-    linked_from: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
-    /// All components this provider is linked to and their config
-    linked_to: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
-}
+    /// The request is handed to the model's batcher in case batching is configured,
+    /// and run right away otherwise. With autoload configured, an unknown model is
+    /// loaded beforehand. The request, including the model's load, is limited by
+    /// the earlier of the `deadline` and the model's configured timeout. In case the
+    /// model's queue is full, the request is rejected as overloaded. Requests for an
+    /// alias are admitted, limited and batched as requests for the model the alias
+    /// currently serves.
+    async fn predict_model(
+        &self,
+        model_id: &str,
+        deadline: Option<Instant>,
+        tensors_in: Vec<NamedTensor>,
+    ) -> Result<Vec<NamedTensor>, MlError> {
+        let started = Instant::now();
+        let deadline = self.request_deadline(model_id, started, deadline).await;
+
+        // loading the model counts towards the request's deadline as well
+        let prepared = async {
+            self.restore_alias(model_id).await?;
+            self.autoload(model_id).await?;
+
+            // each request is validated on its own, before it is stacked into a batch
+            let model_context = self.ready_context(model_id).await?;
+            validation::validate_inputs(&tensors_in, &model_context.inputs)?;
+
+            Ok(model_context)
+        };
+        let model_context = until_deadline(model_id, deadline, prepared).await?;
 
-impl InferenceProvider {
-    /// Execute the provider, loading [`HostData`] from the host which includes the provider's configuration and
-    /// information about the host. Once you use the passed configuration to construct a [`InferenceProvider`],
-    /// you can run the provider by calling `run_provider` and then serving the provider's exports on the proper
-    /// RPC topics via `wrpc::serve`.
-    ///
-    /// This step is essentially the same for every provider, and you shouldn't need to modify this function.
-    pub async fn run() -> anyhow::Result<()> {
-        let provider = Self::default();
-        let shutdown = run_provider(provider.clone(), "custom-template-provider")
+        let served_id = self.served_model_id(model_id, &model_context).await;
+        let deadline = match served_id != model_id {
+            true => self.request_deadline(&served_id, started, deadline).await,
+            false => deadline,
+        };
+        let model_id = served_id.as_str();
+
+        let max_queue_depth = self.config.read().await.max_queue_depth(model_id);
+
+        // rejected right away with the queue full, rather than piling up
+        let admission = self.workers().await.admit(model_id, max_queue_depth)?;
+
+        let prediction = async {
+            match self.batcher(model_id).await {
+                Some(batcher) => {
+                    let batch_axis = model_context.batch_axis.unwrap_or(0);
+                    batcher
+                        .submit(tensors_in, batch_axis, admission, deadline)
+                        .await
+                }
+                None => self.infer(model_id, tensors_in, vec![admission], deadline).await,
+            }
+        };
+
+        // an inference which already started keeps its worker busy, and the request
+        // its place in the queue, until it finishes, but the caller is not kept waiting
+        until_deadline(model_id, deadline, prediction).await
+    }
+
+    /// The earlier of the caller's `deadline` and the end of the model's configured
+    /// timeout, which counts from the request's arrival at `started`
+    async fn request_deadline(
+        &self,
+        model_id: &str,
+        started: Instant,
+        deadline: Option<Instant>,
+    ) -> Option<Instant> {
+        let timed = self
+            .config
+            .read()
             .await
-            .context("failed to run provider")?;
+            .timeout(model_id)
+            .map(|timeout| started + timeout);
 
-        // The [`serve`] function will set up RPC topics for your provider's exports and await invocations.
-        // This is a generated function based on the contents in your `wit/world.wit` file.
-        let connection = wasmcloud_provider_sdk::get_connection();
+        match (deadline, timed) {
+            (Some(deadline), Some(timed)) => Some(deadline.min(timed)),
+            (deadline, timed) => deadline.or(timed),
+        }
+    }
 
-        serve_provider_exports(
-            &connection.get_wrpc_client(connection.provider_key()),
-            provider,
-            shutdown,
-            crate::serve,
-        )
-        .await
-        .context("failed to serve provider exports")
+    /// Id of the model which serves the requests for `model_id`, i.e. the model an
+    /// alias currently points at. An alias only serves on its own in case its model
+    /// has been unloaded meanwhile, since the alias keeps the model's graph loaded.
+    async fn served_model_id(&self, model_id: &str, model_context: &ModelContext) -> ModelId {
+        let served_id = &model_context.model_name;
 
-        // serve(
-        //     &connection.get_wrpc_client(connection.provider_key()),
-        //     provider,
-        //     shutdown,
-        // )
-        // .await
+        let is_ready = self
+            .models
+            .read()
+            .await
+            .get(served_id)
+            .is_some_and(|entry| entry.ready_context().is_some());
 
-        // If your provider has no exports, simply await the shutdown to keep the provider running
-        // shutdown.await;
-        // Ok(())
+        match is_ready {
+            true => served_id.clone(),
+            false => model_id.to_owned(),
+        }
     }
 
-    pub async fn get_registry(&self) -> String {
-        let config_guard = self.config.read().await;
+    /// Returns the model's batcher, which is started on first use,
+    /// or `None` in case batching is not configured for the model.
+    async fn batcher(&self, model_id: &str) -> Option<Batcher> {
+        if let Some(batcher) = self.batchers.read().await.get(model_id) {
+            return Some(batcher.clone());
+        }
 
-        let registry = match config_guard.values.get(CONFIG_URL_KEY) {
-            Some(url) => url.to_string(),
-            None => DEFAULT_CONNECT_URL.to_string(),
-        };
+        let batch_config = self.config.read().await.batch_config(model_id)?;
 
-        registry
+        let mut batchers_lock = self.batchers.write().await;
+        let batcher = batchers_lock
+            .entry(model_id.to_owned())
+            .or_insert_with(|| Batcher::spawn(self.clone(), model_id.to_owned(), batch_config));
+
+        Some(batcher.clone())
     }
 
-    pub async fn register_model(
+    /// Run the model registered as `model_id` with the given input tensors.
+    /// Named tensors are assigned to the model's input of that name, unnamed
+    /// ones to the input at their position. Returns all of the model's outputs.
+    /// The inference is not started anymore once the `deadline` has passed.
+    /// The `admissions` of the requests it serves are released along with it.
+    pub async fn infer(
         &self,
         model_id: &str,
-        model_data: ModelRawData,
-    ) -> anyhow::Result<()> {
-        let metadata = model_data.metadata;
+        tensors_in: Vec<NamedTensor>,
+        admissions: Vec<Admission>,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<NamedTensor>, MlError> {
+        let model_context = self.ready_context(model_id).await?;
+
+        if let Some(entry) = self.models.read().await.get(model_id) {
+            entry.touch();
+        }
 
-        let graph_encoding = GraphEncoding::from_str(&metadata.graph_encoding)
-            .map_err(|error| anyhow!(error.to_string()))?;
+        info!("FETCHING ... the inference engine");
 
-        let execution_target = ExecutionTarget::from_str(&metadata.execution_target)
-            .map_err(|error| anyhow!(error.to_string()))?;
+        let engine = get_engine(Arc::clone(&self.engines), &model_context.graph_encoding).await?;
 
-        let data_type = DataType::from_str(&metadata.execution_target)
-            .map_err(|error| anyhow!(error.to_string()))?;
+        info!("AWAITING ... the result");
 
-        let engine = get_or_else_set_engine(Arc::clone(&self.engines), &graph_encoding).await?;
+        let gec = model_context.graph_execution_context;
+        let graph_handle = self.graph_handle(&model_context).await?;
 
-        let graph: Graph = engine
-            .load(&model_data.model)
-            .await
-            .map_err(|error| anyhow!(error.to_string()))?;
+        self.workers().await.run(model_id, admissions, deadline, async move {
+            // the graph is kept alive until the inference is done, even if unloaded meanwhile
+            let _graph_handle = graph_handle;
 
-        let gec: GraphExecutionContext = engine
-            .init_execution_context(graph, &execution_target, &graph_encoding)
-            .await
-            .map_err(|error| anyhow!(error.to_string()))?;
+            let input_names = engine.input_names(gec).await?;
 
-        let model_context = ModelContext {
-            model_name: model_id.to_owned(),
-            graph_encoding: graph_encoding,
-            execution_target: execution_target,
-            dtype: data_type,
-            graph: graph,
-            graph_execution_context: gec,
-        };
+            let mut inputs = Vec::with_capacity(tensors_in.len());
+            for (position, tensor_in) in tensors_in.into_iter().enumerate() {
+                let index = match tensor_in.name {
+                    Some(ref name) => index_of(name, &input_names)?,
+                    None => position as u32,
+                };
+                inputs.push((index, tensor_in.tensor));
+            }
 
-        let mut models_lock = self.models.write().await;
+            let tensors_out = match engine.infer(gec, inputs).await {
+                Ok(tensors_out) => tensors_out,
+                Err(e) => {
+                    log::error!("infer() - inference engine failed in 'infer()' with '{}'", e);
+                    return Err(MlError::from(e));
+                }
+            };
+
+            let output_names = engine.output_names(gec).await?;
+
+            Ok(output_names
+                .into_iter()
+                .map(Some)
+                .zip(tensors_out)
+                .map(|(name, tensor)| NamedTensor { name, tensor })
+                .collect())
+        })
+        .await?
+    }
 
-        if let Some(already_context) = models_lock.insert(model_id.to_owned(), model_context) {
-            log::warn!(
-                "model '{}' is already registered: {:?}",
-                model_id,
-                already_context
-            );
-        };
-        Ok(())
+    /// The threads running inferences, started with the configured number of
+    /// threads on first use. Inferences are CPU-bound and must not block the
+    /// runtime, which keeps serving other requests in the meantime.
+    async fn workers(&self) -> &WorkerPool {
+        if let Some(workers) = self.workers.get() {
+            return workers;
+        }
+
+        let count = self.config.read().await.inference_workers();
+
+        self.workers
+            .get_or_init(|| WorkerPool::start(count, tokio::runtime::Handle::current()))
     }
 }
 
@@ -161,74 +1454,67 @@ impl Handler<Option<Context>> for InferenceProvider {
         _ctx: Option<Context>,
         model_id: String,
         tensor_in: Tensor,
+        options: Option<PredictOptions>,
     ) -> anyhow::Result<Result<Tensor, MlError>> {
         info!("PREDICTING ... the future");
 
-        let models_lock = self.models.read().await;
-
-        let model_context = match models_lock.get(&model_id) {
-            Some(mc) => mc.to_owned(),
-            None => {
-                log::error!(
-                    "predict() - model '{}' not found in models {:?}",
-                    &model_id,
-                    &models_lock
-                );
-                return Ok(Err(MlError::ContextNotFoundError(format!(
-                    "Model '{}' is unknown",
-                    &model_id
-                ))));
-            }
-        };
-
-        info!("FETCHING ... the inference engine");
+        let tensors_in = vec![NamedTensor {
+            name: None,
+            tensor: tensor_in,
+        }];
 
-        let engine = get_engine(Arc::clone(&self.engines), &model_context.graph_encoding).await?;
+        let routing_key = options.as_ref().and_then(|o| o.routing_key.as_deref());
+        let deadline = options.as_ref().and_then(requested_deadline);
 
-        info!("AWAITING ... the result");
+        let prediction = self.predict_tensors(&model_id, routing_key, deadline, tensors_in);
+        let mut tensors_out = match prediction.await {
+            Ok(tensors_out) => tensors_out,
+            Err(error) => return Ok(Err(error)),
+        };
 
-        let inference_result = tokio::task::spawn_blocking(move || async move {
-            if let Err(e) = engine
-                .set_input(model_context.graph_execution_context, 0, &tensor_in)
-                .await
-            {
-                log::error!(
-                    "predict() - inference engine failed in 'set_input()' with '{}'",
-                    e
-                );
-                return Err(MlError::ContextNotFoundError(e.to_string()));
-            }
+        info!("ASSEMBLING ... the result");
 
-            if let Err(e) = engine.compute(model_context.graph_execution_context).await {
-                log::error!("predict() - GraphExecutionContext not found: {}", e);
-                return Err(MlError::ContextNotFoundError(e.to_string()));
-            }
+        if tensors_out.is_empty() {
+            log::error!("predict() - model '{}' did not produce any output", &model_id);
+            return Ok(Err(MlError::RuntimeError(format!(
+                "Model '{}' did not produce any output",
+                &model_id
+            ))));
+        }
 
-            let result = engine
-                .get_output(model_context.graph_execution_context, 0)
-                .await;
+        Ok(Ok(tensors_out.swap_remove(0).tensor))
+    }
 
-            Ok(result)
+    async fn predict_many(
+        &self,
+        _ctx: Option<Context>,
+        model_id: String,
+        tensors: Vec<NamedTensor>,
+        options: Option<PredictOptions>,
+    ) -> anyhow::Result<Result<Vec<NamedTensor>, MlError>> {
+        info!(
+            "predicting model '{}' with {} input tensor(s)",
+            model_id,
+            tensors.len()
+        );
 
-        })
-        .await
-        .map_err(|e| MlError::Internal(format!("internal join error: {}", e)))?
-        .await?;
+        let routing_key = options.as_ref().and_then(|o| o.routing_key.as_deref());
+        let deadline = options.as_ref().and_then(requested_deadline);
 
-        info!("ASSEMBLING ... the result");
+        match self.predict_tensors(&model_id, routing_key, deadline, tensors).await {
+            Ok(tensors_out) => Ok(Ok(tensors_out)),
+            Err(error) => Ok(Err(error)),
+        }
+    }
 
-        if let Err(error) = inference_result {
-            log::error!("predict() - problem collecting results {}", error);
-            return Ok(Err(MlError::Internal(error.to_string())));
-        };
+    async fn get_model_info(
+        &self,
+        _ctx: Option<Context>,
+        model_id: String,
+    ) -> anyhow::Result<Result<ModelInfo, MlError>> {
+        info!("describing model '{}'", model_id);
 
-        match inference_result {
-            Err(error) => {
-                log::error!("predict() - problem collecting results {}", error);
-                Ok(Err(MlError::Internal(error.to_string())))
-            }
-            Ok(tensor_out) => Ok(Ok(tensor_out)),
-        }
+        Ok(self.model_info(&model_id).await)
     }
 
     async fn prefetch(
@@ -238,39 +1524,216 @@ impl Handler<Option<Context>> for InferenceProvider {
     ) -> anyhow::Result<Result<(), MlError>> {
         info!("prefetching model '{}'", model_id);
 
-        let registry = self.get_registry().await;
+        self.prefetch_model(&model_id).await;
+
+        Ok(Ok(()))
+    }
 
-        let model_data = data_loader::fetch_model(&registry, &model_id)
+    async fn model_status(
+        &self,
+        _ctx: Option<Context>,
+        model_id: String,
+    ) -> anyhow::Result<Result<crate::LoadState, MlError>> {
+        Ok(self
+            .model_state(&model_id)
             .await
-            .map_err(|error| MlError::Internal(error.to_string()))?;
+            .map(|state| crate::LoadState::from(&state)))
+    }
 
-        self.register_model(&model_id, model_data).await?;
+    async fn list_models(&self, _ctx: Option<Context>) -> anyhow::Result<Vec<ModelSummary>> {
+        Ok(self.model_summaries().await)
+    }
 
-        Ok(Ok(()))
+    async fn set_alias(
+        &self,
+        _ctx: Option<Context>,
+        alias: String,
+        model_id: String,
+    ) -> anyhow::Result<Result<(), MlError>> {
+        info!("pointing alias '{}' at model '{}'", alias, model_id);
+
+        Ok(self.point_alias(&alias, &model_id).await)
+    }
+
+    async fn set_route(
+        &self,
+        _ctx: Option<Context>,
+        name: String,
+        targets: Vec<RouteTarget>,
+    ) -> anyhow::Result<Result<(), MlError>> {
+        info!("routing '{}' to {:?}", name, targets);
+
+        Ok(self.update_route(&name, targets).await)
+    }
+
+    async fn route_stats(
+        &self,
+        _ctx: Option<Context>,
+        name: String,
+    ) -> anyhow::Result<Result<Vec<RouteVersionStats>, MlError>> {
+        Ok(self.route_version_stats(&name).await)
+    }
+
+    async fn shadow_comparison(
+        &self,
+        _ctx: Option<Context>,
+        model_id: String,
+    ) -> anyhow::Result<Result<ShadowReport, MlError>> {
+        Ok(self.shadow_comparison(&model_id).await)
     }
 
     async fn preempt(
         &self,
         _ctx: Option<Context>,
         model_id: String,
-    ) -> anyhow::Result<Result<(), MlError>> {
+    ) -> anyhow::Result<Result<u64, MlError>> {
         info!("preempting model '{}'", model_id);
 
-        let mut models_lock = self.models.write().await;
+        match self.unload_model(&model_id).await {
+            Ok(reclaimed) => Ok(Ok(reclaimed as u64)),
+            Err(error) => Ok(Err(error)),
+        }
+    }
+}
 
-        match models_lock.get(&model_id) {
-            None => {
-                return Ok(Err(MlError::ContextNotFoundError(format!(
-                    "No model registered under '{}'",
-                    &model_id
-                ))));
-            },
+/// Models sharing a graph, which is evicted as a whole
+#[derive(Default)]
+struct EvictionCandidate {
+    /// most recent activity of any of the models
+    last_active: u64,
+    model_ids: Vec<ModelId>,
+    aliases: Vec<String>,
+    pinned: bool,
+}
+
+/// Graphs being loaded and their size in bytes
+#[derive(Debug, Default)]
+struct ReservedRoom {
+    graphs: usize,
+    bytes: usize,
+}
+
+/// Room reserved for a graph being loaded, which is given back when dropped
+struct Reservation {
+    reserved: Arc<std::sync::Mutex<ReservedRoom>>,
+    size: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut reserved = self.reserved.lock().unwrap_or_else(|e| e.into_inner());
+        reserved.graphs -= 1;
+        reserved.bytes -= self.size;
+    }
+}
+
+/// Backoff of the first retry of a model which failed to autoload,
+/// which doubles with each further failure
+const AUTOLOAD_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound of the backoff of a model which failed to autoload
+const AUTOLOAD_MAX_BACKOFF: Duration = Duration::from_secs(300);
 
-            Some(_) => {
-                models_lock.remove(&model_id);
-            } 
+/// Failed autoloads of a model in a row
+struct AutoloadFailure {
+    failures: u32,
+    reason: String,
+    /// point in time before which the model is not fetched again
+    retry_at: Instant,
+}
+
+impl AutoloadFailure {
+    /// A model which has not failed yet, see `failed()`
+    fn new(reason: &str) -> Self {
+        Self {
+            failures: 0,
+            reason: reason.to_owned(),
+            retry_at: Instant::now(),
         }
-        Ok(Ok(()))
+    }
+
+    /// Records another failure at `now`, which doubles the backoff
+    fn failed(&mut self, reason: &str, now: Instant) {
+        self.failures += 1;
+        self.reason = reason.to_owned();
+        self.retry_at = now + autoload_backoff(self.failures);
+    }
+
+    /// Time until the model may be fetched again, `None` if it may right away
+    fn retry_after(&self) -> Option<Duration> {
+        let retry_after = self.retry_at.saturating_duration_since(Instant::now());
+        Some(retry_after).filter(|retry_after| !retry_after.is_zero())
+    }
+}
+
+/// Backoff after given number of failed autoloads in a row
+fn autoload_backoff(failures: u32) -> Duration {
+    AUTOLOAD_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(AUTOLOAD_MAX_BACKOFF)
+}
+
+/// Deadline of a prediction as requested by its caller, if any
+fn requested_deadline(options: &PredictOptions) -> Option<Instant> {
+    options
+        .timeout_ms
+        .map(|millis| Instant::now() + Duration::from_millis(millis))
+}
+
+/// Awaits the `future`, which fails with a timeout once the `deadline` passes
+async fn until_deadline<T>(
+    model_id: &str,
+    deadline: Option<Instant>,
+    future: impl std::future::Future<Output = Result<T, MlError>>,
+) -> Result<T, MlError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future)
+            .await
+            .unwrap_or_else(|_| Err(timed_out(model_id))),
+        None => future.await,
+    }
+}
+
+fn timed_out(model_id: &str) -> MlError {
+    MlError::Timeout(format!("Model '{}' did not answer before the deadline", model_id))
+}
+
+/// A tensor of zeros as described by `info`, `None` unless its shape and type are known.
+fn zero_tensor(info: &TensorInfo) -> Option<Tensor> {
+    let shape = info
+        .shape
+        .as_ref()?
+        .iter()
+        .map(|dim| match dim {
+            DimensionInfo::Fixed(size) => Some(*size),
+            DimensionInfo::Symbolic(_) => None,
+        })
+        .collect::<Option<Vec<u32>>>()?;
+
+    let dtype = info.dtype?;
+    let len = shape.iter().map(|&d| d as usize).product::<usize>() * element_size(&dtype)?;
+
+    Some(Tensor {
+        shape,
+        dtype,
+        data: vec![0; len].into(),
+    })
+}
+
+/// Fills in what is left open about a tensor by what the model's metadata declares.
+fn complement_tensor_info(
+    info: &mut TensorInfo,
+    shape: &Option<Vec<u32>>,
+    dtype: Option<DataType>,
+) {
+    if info.shape.is_none() {
+        info.shape = shape
+            .as_ref()
+            .map(|dims| dims.iter().map(|&d| DimensionInfo::Fixed(d)).collect());
+    }
+
+    if info.dtype.is_none() {
+        info.dtype = dtype;
     }
 }
 
@@ -286,20 +1749,30 @@ impl Provider for InferenceProvider {
         // Save configuration to provider state
         *self.config.write().await = ProviderConfig::from(initial_config);
 
-        let config_lock = self.config.read().await;
-
-        let registry = self.get_registry().await;
+        let (model_ids, aliases): (Vec<String>, Vec<(String, String)>) = {
+            let config = self.config.read().await;
+            (
+                config
+                    .models()
+                    .map(|(_, image_ref)| image_ref.to_owned())
+                    .collect(),
+                config
+                    .aliases()
+                    .map(|(alias, model_id)| (alias.to_owned(), model_id.to_owned()))
+                    .collect(),
+            )
+        };
 
-        for (_, image_ref) in config_lock
-            .values
-            .iter()
-            .filter(|(k, _)| !k.starts_with("url"))
-        {
-            let model_data = data_loader::fetch_model(&registry, &image_ref)
-                .await
-                .map_err(|error| MlError::Internal(error.to_string()))?;
+        // models are loaded in the background, such that
+        // a single failing model does not abort the provider
+        for model_id in model_ids {
+            self.prefetch_model(&model_id).await;
+        }
 
-            self.register_model(&image_ref, model_data).await?;
+        for (alias, model_id) in aliases {
+            if let Err(error) = self.point_alias(&alias, &model_id).await {
+                log::warn!("alias '{}' cannot point at '{}': {:?}", alias, model_id, error);
+            }
         }
 
         Ok(())
@@ -413,3 +1886,28 @@ impl Provider for InferenceProvider {
 }
 
 impl InferenceProvider {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autoload_backoff_doubles_up_to_its_bound() {
+        assert_eq!(autoload_backoff(1), AUTOLOAD_BACKOFF);
+        assert_eq!(autoload_backoff(2), AUTOLOAD_BACKOFF * 2);
+        assert_eq!(autoload_backoff(3), AUTOLOAD_BACKOFF * 4);
+        assert_eq!(autoload_backoff(40), AUTOLOAD_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failed_autoloads_are_retried_after_their_backoff() {
+        let mut failure = AutoloadFailure::new("registry unavailable");
+        assert!(failure.retry_after().is_none());
+
+        failure.failed("registry unavailable", Instant::now());
+        assert!(failure.retry_after().is_some());
+
+        failure.retry_at = Instant::now();
+        assert!(failure.retry_after().is_none());
+    }
+}
@@ -0,0 +1,255 @@
+//! Weighted routing of a logical model onto several model versions.
+//!
+//! Requests for a route are spread across its versions by weight, e.g. 90/10
+//! for a canary release. Requests which come with a routing key are routed
+//! sticky, i.e. the very same key is always served by the very same version.
+
+use crate::engine::ModelId;
+use crate::{MlError, RouteTarget, RouteVersionStats};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A model version the route spreads requests to
+#[derive(Debug)]
+struct Version {
+    model_id: ModelId,
+    weight: u32,
+    answered: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Routing of a logical model onto its versions. Selecting a version and
+/// counting the outcomes only take a shared reference, such that requests
+/// for the route do not serialize on the map of routes.
+#[derive(Debug)]
+pub struct Route {
+    name: String,
+    versions: Vec<Version>,
+    /// current weights of the smooth weighted round robin, one per version
+    current: Mutex<Vec<i64>>,
+}
+
+impl Route {
+    /// Creates the route, taking over the counters of versions which
+    /// were already part of the `previous` route.
+    pub fn new(
+        name: &str,
+        targets: Vec<RouteTarget>,
+        previous: Option<&Route>,
+    ) -> Result<Self, MlError> {
+        if targets.iter().all(|target| target.weight == 0) {
+            return Err(MlError::Internal(format!(
+                "Route '{}' requires at least one version with a weight larger than 0",
+                name
+            )));
+        }
+
+        let versions: Vec<Version> = targets
+            .into_iter()
+            .map(|target| {
+                let counted = previous.and_then(|route| {
+                    route.versions.iter().find(|v| v.model_id == target.model_id)
+                });
+                let count = |counter: fn(&Version) -> &AtomicU64| {
+                    counted.map_or(0, |v| counter(v).load(Ordering::Relaxed))
+                };
+
+                Version {
+                    answered: AtomicU64::new(count(|v| &v.answered)),
+                    failed: AtomicU64::new(count(|v| &v.failed)),
+                    model_id: target.model_id,
+                    weight: target.weight,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            name: name.to_owned(),
+            current: Mutex::new(vec![0; versions.len()]),
+            versions,
+        })
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.versions.iter().map(|v| v.weight as u64).sum()
+    }
+
+    /// Selects the version to serve a request. With a `key`, the selection is
+    /// derived from the key's hash, otherwise by smooth weighted round robin.
+    pub fn select(&self, key: Option<&str>) -> ModelId {
+        match key {
+            Some(key) => self.select_sticky(key),
+            None => self.select_round_robin(),
+        }
+    }
+
+    fn select_sticky(&self, key: &str) -> ModelId {
+        let mut point = sticky_hash(&self.name, key) % self.total_weight();
+
+        for version in self.versions.iter() {
+            if point < version.weight as u64 {
+                return version.model_id.clone();
+            }
+            point -= version.weight as u64;
+        }
+
+        // unreachable, since the point is less than the total weight
+        self.versions[0].model_id.clone()
+    }
+
+    /// Smooth weighted round robin, which interleaves the versions
+    /// instead of serving them in bursts.
+    fn select_round_robin(&self) -> ModelId {
+        let total = self.total_weight() as i64;
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+
+        for (current, version) in current.iter_mut().zip(self.versions.iter()) {
+            *current += version.weight as i64;
+        }
+
+        let selected = (0..current.len())
+            .max_by_key(|&index| current[index])
+            .expect("a route has at least one version");
+
+        current[selected] -= total;
+        self.versions[selected].model_id.clone()
+    }
+
+    /// Counts the outcome of a request served by the version `model_id`.
+    pub fn record(&self, model_id: &str, success: bool) {
+        if let Some(version) = self.versions.iter().find(|v| v.model_id == model_id) {
+            let counter = match success {
+                true => &version.answered,
+                false => &version.failed,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> Vec<RouteVersionStats> {
+        self.versions
+            .iter()
+            .map(|version| RouteVersionStats {
+                model_id: version.model_id.clone(),
+                weight: version.weight,
+                answered: version.answered.load(Ordering::Relaxed),
+                failed: version.failed.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// 64 bit FNV-1a hash of the route's name and the routing key. Unlike the
+/// std hashers, it is the same across processes and releases, hence a key
+/// keeps being served by the same version after a restart.
+fn sticky_hash(name: &str, key: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    // the separator keeps e.g. ("ab", "c") and ("a", "bc") apart
+    name.bytes()
+        .chain([0xff])
+        .chain(key.bytes())
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(PRIME)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(weights: &[(&str, u32)]) -> Route {
+        let targets = weights
+            .iter()
+            .map(|&(model_id, weight)| RouteTarget {
+                model_id: model_id.to_string(),
+                weight,
+            })
+            .collect();
+
+        Route::new("classifier", targets, None).unwrap()
+    }
+
+    fn count(selected: &[ModelId], model_id: &str) -> usize {
+        selected.iter().filter(|id| *id == model_id).count()
+    }
+
+    #[test]
+    fn requests_without_key_are_spread_by_weight() {
+        let route = route(&[("stable", 90), ("canary", 10)]);
+
+        let selected: Vec<ModelId> = (0..1000).map(|_| route.select(None)).collect();
+
+        assert_eq!(count(&selected, "stable"), 900);
+        assert_eq!(count(&selected, "canary"), 100);
+    }
+
+    #[test]
+    fn requests_without_key_interleave_the_versions() {
+        let route = route(&[("a", 2), ("b", 1)]);
+
+        let selected: Vec<ModelId> = (0..6).map(|_| route.select(None)).collect();
+
+        assert_eq!(selected, vec!["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn requests_with_key_are_sticky() {
+        let route = route(&[("stable", 50), ("canary", 50)]);
+
+        for key in ["alice", "bob", "carol"] {
+            let first = route.select(Some(key));
+
+            for _ in 0..100 {
+                // round robin in between does not affect keyed requests
+                route.select(None);
+                assert_eq!(route.select(Some(key)), first);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_are_spread_by_weight() {
+        let route = route(&[("stable", 75), ("canary", 25)]);
+
+        let selected: Vec<ModelId> = (0..4000)
+            .map(|user| route.select(Some(&format!("user-{}", user))))
+            .collect();
+
+        let canary = count(&selected, "canary");
+        assert!((800..1200).contains(&canary), "canary served {} of 4000 keys", canary);
+    }
+
+    #[test]
+    fn route_requires_a_positive_weight() {
+        let targets = vec![RouteTarget {
+            model_id: "stable".to_string(),
+            weight: 0,
+        }];
+
+        assert!(Route::new("classifier", targets, None).is_err());
+    }
+
+    #[test]
+    fn sticky_hash_is_stable() {
+        assert_eq!(sticky_hash("classifier", "alice"), 0xe12f_1418_5edb_0c8b);
+    }
+
+    #[test]
+    fn updated_route_keeps_the_counters() {
+        let route = route(&[("stable", 90), ("canary", 10)]);
+        route.record("stable", true);
+        route.record("canary", false);
+
+        let targets = vec![RouteTarget {
+            model_id: "canary".to_string(),
+            weight: 100,
+        }];
+        let updated = Route::new("classifier", targets, Some(&route)).unwrap();
+
+        let stats = updated.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].answered, stats[0].failed), (0, 1));
+    }
+}
@@ -0,0 +1,151 @@
+//! Shadow inference of candidate models.
+//!
+//! Requests for a production model are mirrored to its shadow model in the
+//! background. The caller only ever receives the production model's result,
+//! the outputs of both models are compared and accumulated in `ShadowStats`.
+//! Mirroring is best-effort: requests are only mirrored to a shadow model which
+//! is loaded already, and only while the inference workers are not busy.
+
+use crate::engine::ModelId;
+use crate::{DataType, NamedTensor, ShadowReport, Tensor};
+use std::time::Duration;
+use tract_onnx::prelude::f16;
+
+/// How far the outputs of the production and the shadow model diverge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Comparison {
+    /// Whether the first outputs have their largest value at the same index
+    pub top1_agrees: bool,
+    /// Largest absolute difference between the outputs' values,
+    /// infinite in case the outputs don't match in number, shape or type.
+    pub max_abs_diff: f64,
+}
+
+/// Accumulated comparisons of a production model with its shadow model
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShadowStats {
+    shadow_model_id: ModelId,
+    compared: u64,
+    failed: u64,
+    top1_agreements: u64,
+    max_abs_diff: f64,
+    primary_latency: Duration,
+    shadow_latency: Duration,
+}
+
+impl ShadowStats {
+    pub fn new(shadow_model_id: &str) -> Self {
+        Self {
+            shadow_model_id: shadow_model_id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn shadow_model_id(&self) -> &str {
+        &self.shadow_model_id
+    }
+
+    pub fn record(
+        &mut self,
+        comparison: Comparison,
+        primary_latency: Duration,
+        shadow_latency: Duration,
+    ) {
+        self.compared += 1;
+        self.top1_agreements += comparison.top1_agrees as u64;
+        self.max_abs_diff = self.max_abs_diff.max(comparison.max_abs_diff);
+        self.primary_latency += primary_latency;
+        self.shadow_latency += shadow_latency;
+    }
+
+    /// Counts a request the shadow model failed on.
+    pub fn record_failure(&mut self) {
+        self.failed += 1;
+    }
+
+    pub fn report(&self) -> ShadowReport {
+        let mean_ms = |total: Duration| match self.compared {
+            0 => 0.0,
+            n => total.as_secs_f64() * 1000.0 / n as f64,
+        };
+
+        ShadowReport {
+            shadow_model_id: self.shadow_model_id.clone(),
+            compared: self.compared,
+            failed: self.failed,
+            top1_agreements: self.top1_agreements,
+            max_abs_diff: self.max_abs_diff,
+            primary_latency_ms: mean_ms(self.primary_latency),
+            shadow_latency_ms: mean_ms(self.shadow_latency),
+        }
+    }
+}
+
+/// Compares the outputs of the production model with those of the shadow model.
+pub fn compare(primary: &[NamedTensor], shadow: &[NamedTensor]) -> Comparison {
+    let top1_agrees = match (primary.first(), shadow.first()) {
+        (Some(p), Some(s)) => match (values(&p.tensor), values(&s.tensor)) {
+            (Some(p), Some(s)) => argmax(&p).is_some() && argmax(&p) == argmax(&s),
+            _ => false,
+        },
+        _ => false,
+    };
+
+    let mut max_abs_diff = match primary.len() == shadow.len() {
+        true => 0.0,
+        false => f64::INFINITY,
+    };
+
+    for (p, s) in primary.iter().zip(shadow.iter()) {
+        let diff = match (values(&p.tensor), values(&s.tensor)) {
+            (Some(pv), Some(sv)) if p.tensor.shape == s.tensor.shape => pv
+                .iter()
+                .zip(sv.iter())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f64::max),
+            _ => f64::INFINITY,
+        };
+        max_abs_diff = f64::max(max_abs_diff, diff);
+    }
+
+    Comparison {
+        top1_agrees,
+        max_abs_diff,
+    }
+}
+
+fn argmax(values: &[f64]) -> Option<usize> {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+}
+
+/// Decodes a tensor's little endian data, `None` for unsupported data types.
+fn values(tensor: &Tensor) -> Option<Vec<f64>> {
+    let data = &tensor.data;
+
+    let values = match tensor.dtype {
+        DataType::U8 => data.iter().map(|&v| v as f64).collect(),
+        DataType::S8 => data.iter().map(|&v| v as i8 as f64).collect(),
+        DataType::U16 => decode(data, |b| u16::from_le_bytes(b) as f64),
+        DataType::S16 => decode(data, |b| i16::from_le_bytes(b) as f64),
+        DataType::F16 => decode(data, |b| f16::from_le_bytes(b).to_f64()),
+        DataType::U32 => decode(data, |b| u32::from_le_bytes(b) as f64),
+        DataType::S32 => decode(data, |b| i32::from_le_bytes(b) as f64),
+        DataType::F32 => decode(data, |b| f32::from_le_bytes(b) as f64),
+        DataType::U64 => decode(data, |b| u64::from_le_bytes(b) as f64),
+        DataType::S64 => decode(data, |b| i64::from_le_bytes(b) as f64),
+        DataType::F64 => decode(data, f64::from_le_bytes),
+        _ => return None,
+    };
+
+    Some(values)
+}
+
+fn decode<const N: usize>(data: &[u8], from_le_bytes: impl Fn([u8; N]) -> f64) -> Vec<f64> {
+    data.chunks_exact(N)
+        .map(|chunk| from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
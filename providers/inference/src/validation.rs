@@ -0,0 +1,195 @@
+//! Validation of input tensors before inference.
+//!
+//! Each tensor is checked against the input it is assigned to, as declared by
+//! the model's graph and complemented by the model's metadata. Mismatches are
+//! reported as `corrupt-input-tensor`, naming the expected and the actual value,
+//! rather than surfacing as an opaque error of the engine.
+
+use crate::engine::element_size;
+use crate::{DimensionInfo, MlError, NamedTensor, Tensor, TensorInfo};
+
+/// Validates the tensors of a request against the model's `inputs`. Named
+/// tensors are matched with the input of that name, unnamed ones with the input
+/// at their position. Whatever the model leaves open is not validated.
+pub fn validate_inputs(tensors: &[NamedTensor], inputs: &[TensorInfo]) -> Result<(), MlError> {
+    for (position, named) in tensors.iter().enumerate() {
+        let (label, declared) = match named.name {
+            Some(ref name) => (name.clone(), inputs.iter().find(|input| &input.name == name)),
+            None => match inputs.get(position) {
+                Some(input) => (input.name.clone(), Some(input)),
+                None => (format!("#{}", position), None),
+            },
+        };
+
+        if let Some(declared) = declared {
+            validate_declared(&label, &named.tensor, declared)?;
+        }
+
+        validate_length(&label, &named.tensor)?;
+    }
+
+    Ok(())
+}
+
+fn validate_declared(label: &str, tensor: &Tensor, declared: &TensorInfo) -> Result<(), MlError> {
+    if let Some(dtype) = declared.dtype {
+        if dtype != tensor.dtype {
+            return Err(MlError::CorruptInputTensor(format!(
+                "Input '{}' expects data type {:?}, but got {:?}",
+                label, dtype, tensor.dtype
+            )));
+        }
+    }
+
+    if let Some(ref shape) = declared.shape {
+        let matches = shape.len() == tensor.shape.len()
+            && shape.iter().zip(tensor.shape.iter()).all(|(dim, &size)| match dim {
+                DimensionInfo::Fixed(expected) => *expected == size,
+                DimensionInfo::Symbolic(_) => true,
+            });
+
+        if !matches {
+            return Err(MlError::CorruptInputTensor(format!(
+                "Input '{}' expects shape {}, but got {:?}",
+                label,
+                shape_to_string(shape),
+                tensor.shape
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// The data must hold exactly as many bytes as the tensor's shape and type require.
+fn validate_length(label: &str, tensor: &Tensor) -> Result<(), MlError> {
+    // tensors without data type are left to the engine, which rejects them
+    let size = match element_size(&tensor.dtype) {
+        Some(size) => size,
+        None => return Ok(()),
+    };
+
+    let expected = tensor.shape.iter().map(|&d| d as usize).product::<usize>() * size;
+
+    if tensor.data.len() != expected {
+        return Err(MlError::CorruptInputTensor(format!(
+            "Input '{}' of shape {:?} and data type {:?} expects {} bytes, but got {}",
+            label,
+            tensor.shape,
+            tensor.dtype,
+            expected,
+            tensor.data.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Formats a declared shape like `[N, 3, 224, 224]`
+fn shape_to_string(shape: &[DimensionInfo]) -> String {
+    let dims: Vec<String> = shape
+        .iter()
+        .map(|dim| match dim {
+            DimensionInfo::Fixed(size) => size.to_string(),
+            DimensionInfo::Symbolic(name) => name.clone(),
+        })
+        .collect();
+
+    format!("[{}]", dims.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataType;
+
+    fn input(name: &str, dtype: Option<DataType>, shape: Option<Vec<DimensionInfo>>) -> TensorInfo {
+        TensorInfo {
+            name: name.to_string(),
+            shape,
+            dtype,
+        }
+    }
+
+    fn image_input() -> TensorInfo {
+        input(
+            "image",
+            Some(DataType::F32),
+            Some(vec![
+                DimensionInfo::Symbolic("N".to_string()),
+                DimensionInfo::Fixed(3),
+                DimensionInfo::Fixed(2),
+            ]),
+        )
+    }
+
+    fn tensor(name: Option<&str>, dtype: DataType, shape: Vec<u32>, len: usize) -> NamedTensor {
+        NamedTensor {
+            name: name.map(str::to_string),
+            tensor: Tensor {
+                shape,
+                dtype,
+                data: vec![0u8; len].into(),
+            },
+        }
+    }
+
+    #[test]
+    fn matching_tensors_are_valid() {
+        let inputs = [image_input()];
+
+        let named = tensor(Some("image"), DataType::F32, vec![1, 3, 2], 24);
+        assert!(validate_inputs(&[named], &inputs).is_ok());
+
+        let unnamed = tensor(None, DataType::F32, vec![1, 3, 2], 24);
+        assert!(validate_inputs(&[unnamed], &inputs).is_ok());
+    }
+
+    #[test]
+    fn mismatched_dtype_is_rejected() {
+        let inputs = [image_input()];
+        let tensors = [tensor(Some("image"), DataType::U8, vec![1, 3, 2], 6)];
+
+        let error = validate_inputs(&tensors, &inputs).unwrap_err();
+        assert!(matches!(error, MlError::CorruptInputTensor(ref msg) if msg.contains("data type")));
+    }
+
+    #[test]
+    fn wrong_rank_is_rejected() {
+        let inputs = [image_input()];
+        let tensors = [tensor(Some("image"), DataType::F32, vec![3, 2], 24)];
+
+        let error = validate_inputs(&tensors, &inputs).unwrap_err();
+        assert!(matches!(error, MlError::CorruptInputTensor(ref msg) if msg.contains("[N, 3, 2]")));
+    }
+
+    #[test]
+    fn symbolic_dims_take_any_size() {
+        let inputs = [image_input()];
+        let tensors = [tensor(Some("image"), DataType::F32, vec![4, 3, 2], 96)];
+        assert!(validate_inputs(&tensors, &inputs).is_ok());
+
+        // fixed dims must still match
+        let tensors = [tensor(Some("image"), DataType::F32, vec![4, 2, 3], 96)];
+        assert!(matches!(
+            validate_inputs(&tensors, &inputs),
+            Err(MlError::CorruptInputTensor(_))
+        ));
+    }
+
+    #[test]
+    fn short_buffer_is_rejected() {
+        let inputs = [image_input()];
+        let tensors = [tensor(Some("image"), DataType::F32, vec![1, 3, 2], 23)];
+
+        let error = validate_inputs(&tensors, &inputs).unwrap_err();
+        assert!(matches!(error, MlError::CorruptInputTensor(ref msg) if msg.contains("expects 24 bytes")));
+
+        // inputs the model does not declare are still checked for their length
+        let tensors = [tensor(Some("mask"), DataType::U8, vec![2, 2], 3)];
+        assert!(matches!(
+            validate_inputs(&tensors, &inputs),
+            Err(MlError::CorruptInputTensor(_))
+        ));
+    }
+}
@@ -0,0 +1,482 @@
+//! Dedicated worker threads for running inferences.
+//!
+//! Inferences are CPU-bound and would stall the async runtime, which serves
+//! the provider's RPCs. Hence they run on a pool of worker threads of their own.
+//! Queued inferences are served round robin by model, such that a heavily
+//! requested model does not starve the others, and each model may be limited
+//! in the number of inferences running at the same time. Inferences whose
+//! deadline passes while they are queued are dropped without being computed.
+//!
+//! Each model admits a bounded number of requests at a time, counting those
+//! queued and running. Further requests are rejected right away as overloaded,
+//! along with an estimate of when the model's queue will be worked off. A request
+//! keeps its place until its inference is finished or dropped, even in case its
+//! caller stopped waiting for it.
+
+use crate::engine::ModelId;
+use crate::{MlError, Overload};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+
+/// Assumed latency of a model's inferences as long as none has been measured yet
+const DEFAULT_LATENCY: Duration = Duration::from_secs(1);
+
+struct Job {
+    inference: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// point in time after which the inference is not worth starting anymore
+    deadline: Option<Instant>,
+    /// places of the requests served by the inference, which are released
+    /// once the inference is finished or dropped
+    admissions: Vec<Admission>,
+}
+
+impl Job {
+    fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+/// Inferences waiting for a worker, by model
+#[derive(Default)]
+struct Queue {
+    pending: HashMap<ModelId, VecDeque<Job>>,
+    /// models with pending inferences, in the order they are served
+    order: VecDeque<ModelId>,
+    /// number of running inferences by model
+    running: HashMap<ModelId, usize>,
+    /// maximum number of running inferences by model, unlimited if missing
+    limits: HashMap<ModelId, usize>,
+    /// moving average of the inferences' latency by model
+    latencies: HashMap<ModelId, Duration>,
+    /// number of worker threads
+    workers: usize,
+}
+
+impl Queue {
+    fn push(&mut self, model_id: &str, job: Job) {
+        let jobs = self.pending.entry(model_id.to_owned()).or_default();
+        if jobs.is_empty() {
+            self.order.push_back(model_id.to_owned());
+        }
+        jobs.push_back(job);
+    }
+
+    /// Takes the next inference of the first model in turn which is below its
+    /// concurrency limit. The model then moves to the back of the line.
+    /// Expired inferences met on the way are dropped, which fails their requests.
+    fn pop(&mut self) -> Option<(ModelId, Job)> {
+        for _ in 0..self.order.len() {
+            let model_id = self.order.pop_front()?;
+
+            let running = self.running.get(&model_id).copied().unwrap_or_default();
+            if self.limits.get(&model_id).is_some_and(|&limit| running >= limit) {
+                self.order.push_back(model_id);
+                continue;
+            }
+
+            let jobs = self.pending.get_mut(&model_id)?;
+            let job = loop {
+                match jobs.pop_front() {
+                    Some(job) if job.is_expired() => {
+                        log::debug!("pop() - dropping expired inference of model '{}'", model_id);
+                    }
+                    next => break next,
+                }
+            };
+
+            let job = match job {
+                Some(job) => job,
+                None => {
+                    self.pending.remove(&model_id);
+                    continue;
+                }
+            };
+
+            match jobs.is_empty() {
+                true => {
+                    self.pending.remove(&model_id);
+                }
+                false => self.order.push_back(model_id.clone()),
+            }
+
+            *self.running.entry(model_id.clone()).or_default() += 1;
+
+            return Some((model_id, job));
+        }
+
+        None
+    }
+
+    fn finish(&mut self, model_id: &str, latency: Duration) {
+        if let Some(running) = self.running.get_mut(model_id) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(model_id);
+            }
+        }
+
+        // recent inferences weigh in with 1/8, such that outliers fade out
+        let average = self
+            .latencies
+            .entry(model_id.to_owned())
+            .or_insert(latency);
+        *average = (*average * 7 + latency) / 8;
+    }
+
+    /// Whether an inference of the model would start right away, i.e. nothing
+    /// is pending, a worker is idle and the model is below its limit
+    fn has_capacity(&self, model_id: &str) -> bool {
+        let busy: usize = self.running.values().sum();
+        let running = self.running.get(model_id).copied().unwrap_or_default();
+
+        self.pending.is_empty()
+            && busy < self.workers
+            && self.limits.get(model_id).is_none_or(|&limit| running < limit)
+    }
+
+    /// Estimated time until the model's `admitted` requests are served
+    fn retry_after(&self, model_id: &str, admitted: usize) -> Duration {
+        let latency = self
+            .latencies
+            .get(model_id)
+            .copied()
+            .unwrap_or(DEFAULT_LATENCY);
+        let parallel = self
+            .limits
+            .get(model_id)
+            .copied()
+            .unwrap_or(self.workers)
+            .clamp(1, self.workers.max(1));
+
+        latency.mul_f64(admitted.max(1) as f64 / parallel as f64)
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// number of admitted requests by model, see `Admission`. It is locked on its
+    /// own, since admissions are released while the queue is locked, e.g. along
+    /// with the expired inferences the queue drops.
+    admitted: Mutex<HashMap<ModelId, usize>>,
+    /// signalled whenever an inference is queued or finished
+    changed: Condvar,
+}
+
+/// Handle to the pool of inference workers
+#[derive(Clone)]
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+}
+
+impl WorkerPool {
+    /// Starts `workers` threads. Inferences are driven to completion on the
+    /// worker threads, the runtime of `handle` only provides its timers and IO.
+    pub fn start(workers: usize, handle: Handle) -> Self {
+        let shared = Arc::new(Shared::default());
+        shared.queue.lock().unwrap().workers = workers.max(1);
+
+        log::info!("starting {} inference workers", workers);
+
+        for index in 0..workers.max(1) {
+            let shared = Arc::clone(&shared);
+            let handle = handle.clone();
+
+            std::thread::Builder::new()
+                .name(format!("inference-worker-{}", index))
+                .spawn(move || work(shared, handle))
+                .expect("failed to spawn inference worker");
+        }
+
+        Self { shared }
+    }
+
+    /// Limits the number of the model's inferences running at the same time to
+    /// `max_concurrency`, unlimited if `None`. The limit is set as the model is
+    /// registered and applies to all of its inferences from then on.
+    pub fn set_limit(&self, model_id: &str, max_concurrency: Option<usize>) {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        match max_concurrency {
+            Some(limit) => queue.limits.insert(model_id.to_owned(), limit),
+            None => queue.limits.remove(model_id),
+        };
+        drop(queue);
+
+        // a raised limit may unblock waiting inferences
+        self.shared.changed.notify_all();
+    }
+
+    /// Whether an inference of the model `model_id` would start right away rather
+    /// than wait for a worker. Optional inferences are skipped unless it would.
+    pub fn has_capacity(&self, model_id: &str) -> bool {
+        self.shared.queue.lock().unwrap().has_capacity(model_id)
+    }
+
+    /// Admits a request for the model `model_id`, unless `max_queue_depth` of its
+    /// requests are queued or running already. The request keeps its place until
+    /// the returned `Admission` is dropped, which is handed over to `run()` for the
+    /// inference serving the request. A rejection carries the estimated time
+    /// until the model's queue is worked off, based on the model's recent latency.
+    pub fn admit(&self, model_id: &str, max_queue_depth: usize) -> Result<Admission, MlError> {
+        let mut admitted_lock = self.shared.admitted.lock().unwrap();
+
+        let admitted = admitted_lock.get(model_id).copied().unwrap_or_default();
+        if admitted >= max_queue_depth {
+            drop(admitted_lock);
+            let retry_after = self.shared.queue.lock().unwrap().retry_after(model_id, admitted);
+
+            log::warn!(
+                "admit() - rejecting request for model '{}', {} requests are queued or running",
+                model_id,
+                admitted
+            );
+
+            return Err(MlError::Overloaded(Overload {
+                message: format!(
+                    "Model '{}' is overloaded with {} requests, retry after {:?}",
+                    model_id, admitted, retry_after
+                ),
+                retry_after_ms: retry_after.as_millis() as u64,
+            }));
+        }
+
+        *admitted_lock.entry(model_id.to_owned()).or_default() += 1;
+
+        Ok(Admission {
+            shared: Arc::clone(&self.shared),
+            model_id: model_id.to_owned(),
+        })
+    }
+
+    /// Queues the inference of the model `model_id` and awaits its result.
+    /// The inference waits while the model is at its limit, see `set_limit()`.
+    /// In case the `deadline` passes before a worker picks up the inference,
+    /// it is never started and fails with `MlError::Timeout`. The `admissions`
+    /// of the requests it serves are held until the inference is finished or
+    /// dropped, even if the caller stops awaiting its result.
+    pub async fn run<T, F>(
+        &self,
+        model_id: &str,
+        admissions: Vec<Admission>,
+        deadline: Option<Instant>,
+        inference: F,
+    ) -> Result<T, MlError>
+    where
+        T: Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (respond_to, response) = oneshot::channel();
+
+        let job = Job {
+            inference: Box::pin(async move {
+                let _ = respond_to.send(inference.await);
+            }),
+            deadline,
+            admissions,
+        };
+
+        self.shared.queue.lock().unwrap().push(model_id, job);
+        self.shared.changed.notify_one();
+
+        response.await.map_err(|_| {
+            let expired = deadline.is_some_and(|deadline| deadline <= Instant::now());
+            match expired {
+                true => MlError::Timeout(format!(
+                    "Deadline of the inference of model '{}' passed while it was queued",
+                    model_id
+                )),
+                false => MlError::Internal("inference worker dropped the request".to_string()),
+            }
+        })
+    }
+}
+
+/// Runs queued inferences, one at a time, for as long as the process lives.
+fn work(shared: Arc<Shared>, handle: Handle) {
+    loop {
+        let (model_id, job) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                match queue.pop() {
+                    Some(next) => break next,
+                    None => queue = shared.changed.wait(queue).unwrap(),
+                }
+            }
+        };
+
+        let Job {
+            inference,
+            admissions,
+            ..
+        } = job;
+        let started = Instant::now();
+
+        // a panicking inference only fails its own request
+        if std::panic::catch_unwind(AssertUnwindSafe(|| handle.block_on(inference))).is_err() {
+            log::error!("work() - inference of model '{}' panicked", model_id);
+        }
+
+        // the requests served leave the queue along with their inference
+        drop(admissions);
+
+        shared
+            .queue
+            .lock()
+            .unwrap()
+            .finish(&model_id, started.elapsed());
+
+        // a model below its limit again may unblock another worker
+        shared.changed.notify_all();
+    }
+}
+
+/// Place of a request in its model's queue, which is freed once dropped
+pub struct Admission {
+    shared: Arc<Shared>,
+    model_id: ModelId,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut admitted_lock = self.shared.admitted.lock().unwrap();
+
+        if let Some(admitted) = admitted_lock.get_mut(&self.model_id) {
+            *admitted -= 1;
+            if *admitted == 0 {
+                admitted_lock.remove(&self.model_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Admission {
+    /// An admission which does not count against any pool
+    pub(crate) fn detached(model_id: &str) -> Self {
+        Self {
+            shared: Arc::new(Shared::default()),
+            model_id: model_id.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(deadline: Option<Instant>) -> Job {
+        Job {
+            inference: Box::pin(async {}),
+            deadline,
+            admissions: Vec::new(),
+        }
+    }
+
+    fn popped(queue: &mut Queue) -> Option<ModelId> {
+        queue.pop().map(|(model_id, _)| model_id)
+    }
+
+    #[test]
+    fn pop_serves_models_round_robin() {
+        let mut queue = Queue::default();
+        for _ in 0..3 {
+            queue.push("a", job(None));
+        }
+        queue.push("b", job(None));
+        queue.push("c", job(None));
+
+        let order: Vec<ModelId> = std::iter::from_fn(|| popped(&mut queue)).collect();
+
+        assert_eq!(order, vec!["a", "b", "c", "a", "a"]);
+        assert!(queue.pending.is_empty());
+        assert!(queue.order.is_empty());
+    }
+
+    #[test]
+    fn pop_respects_the_concurrency_limit() {
+        let mut queue = Queue::default();
+        queue.limits.insert("a".to_string(), 1);
+        queue.push("a", job(None));
+        queue.push("a", job(None));
+        queue.push("b", job(None));
+
+        assert_eq!(popped(&mut queue).as_deref(), Some("a"));
+        assert_eq!(popped(&mut queue).as_deref(), Some("b"));
+        // the second inference of `a` waits for the first one
+        assert_eq!(popped(&mut queue), None);
+
+        queue.finish("a", Duration::from_millis(10));
+        assert_eq!(popped(&mut queue).as_deref(), Some("a"));
+        assert_eq!(queue.running.get("a"), Some(&1));
+    }
+
+    #[test]
+    fn pop_drops_expired_jobs() {
+        let mut queue = Queue::default();
+        let expired = Some(Instant::now());
+        queue.push("a", job(expired));
+        queue.push("a", job(None));
+        queue.push("b", job(expired));
+
+        let (model_id, job) = queue.pop().unwrap();
+        assert_eq!(model_id, "a");
+        assert!(job.deadline.is_none());
+
+        // `b` only holds an expired inference, which is dropped without running
+        assert!(queue.pop().is_none());
+        assert!(queue.pending.is_empty());
+        assert!(!queue.running.contains_key("b"));
+    }
+
+    #[test]
+    fn capacity_requires_an_idle_worker_and_nothing_pending() {
+        let mut queue = Queue {
+            workers: 2,
+            ..Default::default()
+        };
+        queue.limits.insert("a".to_string(), 1);
+        assert!(queue.has_capacity("a"));
+
+        queue.push("a", job(None));
+        assert!(!queue.has_capacity("b"));
+
+        queue.pop();
+        // `a` is at its limit, while another worker is idle
+        assert!(!queue.has_capacity("a"));
+        assert!(queue.has_capacity("b"));
+
+        queue.push("b", job(None));
+        queue.pop();
+        assert!(!queue.has_capacity("b"));
+    }
+
+    #[test]
+    fn dropped_jobs_release_their_admissions() {
+        let pool = WorkerPool {
+            shared: Arc::new(Shared::default()),
+        };
+        let admission = pool.admit("a", 1).unwrap();
+        assert!(matches!(pool.admit("a", 1), Err(MlError::Overloaded(_))));
+
+        let mut queue = pool.shared.queue.lock().unwrap();
+        queue.push(
+            "a",
+            Job {
+                inference: Box::pin(async {}),
+                deadline: Some(Instant::now()),
+                admissions: vec![admission],
+            },
+        );
+        assert!(queue.pop().is_none());
+        drop(queue);
+
+        assert!(pool.admit("a", 1).is_ok());
+    }
+}